@@ -29,8 +29,9 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
-    println!("cargo:rustc-link-arg-bins=--nmagic");
-    println!("cargo:rustc-link-arg-bins=-Tlink.x");
-    println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
-    println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+    // Only the firmware is linked for the board, host binaries use the default linker scripts
+    println!("cargo:rustc-link-arg-bin=pico-soundboard=--nmagic");
+    println!("cargo:rustc-link-arg-bin=pico-soundboard=-Tlink.x");
+    println!("cargo:rustc-link-arg-bin=pico-soundboard=-Tlink-rp.x");
+    println!("cargo:rustc-link-arg-bin=pico-soundboard=-Tdefmt.x");
 }
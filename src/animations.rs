@@ -1,27 +1,53 @@
-use embedded_hal_async::{i2c::I2c, spi::SpiBus};
+use embedded_hal_async::i2c::I2c;
 use rand::{rngs::SmallRng, RngCore};
 
 use crate::{
     board::Board,
-    transitions::{fade_in, fade_out, solid},
-    ButtonState, Colour,
+    transitions::{
+        fade_in, fade_out, flicker, hue_cycle, solid, Easing, Transition, HUE_SPEED_TICKS,
+    },
+    ButtonState, Colour, HUE_RANGE,
 };
 
-pub fn random_fades<I2C: I2c, SPI: SpiBus>(board: &mut Board<I2C, SPI>, small_rng: &mut SmallRng) {
+/// Where the states of an animation are added - the board itself, or requests sent to it by
+/// the host
+pub trait AnimationTarget {
+    fn add_led_state(
+        &mut self,
+        led_idx: usize,
+        state_idx: usize,
+        transition: Transition,
+        for_state: &ButtonState,
+    );
+}
+
+impl<I2C: I2c> AnimationTarget for Board<I2C> {
+    fn add_led_state(
+        &mut self,
+        led_idx: usize,
+        state_idx: usize,
+        transition: Transition,
+        for_state: &ButtonState,
+    ) {
+        Board::add_led_state(self, led_idx, state_idx, transition, for_state);
+    }
+}
+
+pub fn random_fades(board: &mut impl AnimationTarget, small_rng: &mut SmallRng) {
     for i in 0..16 {
         let timeout = small_rng.next_u32() as u16 as usize / 10;
         let colour = Colour::random(small_rng);
         board.add_led_state(
             i,
             0,
-            fade_out(0b11110000, colour, 500, 1),
+            fade_out(0b11110000, colour, 500, 1).with_easing(Easing::Quadratic),
             &ButtonState::Idle,
         );
         board.add_led_state(i, 1, solid(0x00, colour, timeout, 2), &ButtonState::Idle);
         board.add_led_state(
             i,
             2,
-            fade_in(0b11110000, colour, 500, 3),
+            fade_in(0b11110000, colour, 500, 3).with_easing(Easing::Quadratic),
             &ButtonState::Idle,
         );
         board.add_led_state(
@@ -40,7 +66,7 @@ pub fn random_fades<I2C: I2c, SPI: SpiBus>(board: &mut Board<I2C, SPI>, small_rn
         board.add_led_state(
             i,
             1,
-            fade_out(0xff, colour.invert(), 250, 2),
+            fade_out(0xff, colour.invert(), 250, 2).with_easing(Easing::Quadratic),
             &ButtonState::Pressed,
         );
         board.add_led_state(
@@ -52,11 +78,7 @@ pub fn random_fades<I2C: I2c, SPI: SpiBus>(board: &mut Board<I2C, SPI>, small_rn
     }
 }
 
-pub fn loading_circle<I2C: I2c, SPI: SpiBus>(
-    board: &mut Board<I2C, SPI>,
-    colour: Colour,
-    speed: usize,
-) {
+pub fn loading_circle(board: &mut impl AnimationTarget, colour: Colour, speed: usize) {
     for (idx, i) in [0, 1, 2, 3, 7, 11, 15, 14, 13, 12, 8, 4]
         .into_iter()
         .enumerate()
@@ -101,15 +123,47 @@ pub fn loading_circle<I2C: I2c, SPI: SpiBus>(
     }
 }
 
-pub fn breathing<I2C: I2c, SPI: SpiBus>(
-    board: &mut Board<I2C, SPI>,
+pub fn breathing(
+    board: &mut impl AnimationTarget,
     led_index: usize,
     state: &ButtonState,
     colour: Colour,
     speed: usize,
 ) {
-    board.add_led_state(led_index, 0, fade_out(0b11110000, colour, speed, 1), state);
+    let fade_out = fade_out(0b11110000, colour, speed, 1).with_easing(Easing::EaseInOut);
+    let fade_in = fade_in(0b11110000, colour, speed, 3).with_easing(Easing::EaseInOut);
+    board.add_led_state(led_index, 0, fade_out, state);
     board.add_led_state(led_index, 1, solid(0x00, colour, speed, 2), state);
-    board.add_led_state(led_index, 2, fade_in(0b11110000, colour, speed, 3), state);
+    board.add_led_state(led_index, 2, fade_in, state);
     board.add_led_state(led_index, 3, solid(0b11110000, colour, speed, 0), state);
 }
+
+/// Rainbow across the grid, each diagonal a bit further around the colour wheel. A full turn
+/// takes `period` ticks.
+pub fn rainbow(board: &mut impl AnimationTarget, period: usize) {
+    let speed = HUE_RANGE as usize * HUE_SPEED_TICKS / period.max(1);
+    let speed = speed.min(i16::MAX as usize) as i16;
+    for i in 0..16 {
+        let (row, column) = (i / 4, i % 4);
+        let start_hue = ((row + column) * HUE_RANGE as usize / 8) as u16;
+        board.add_led_state(
+            i,
+            0,
+            hue_cycle(start_hue, speed, 0xff, 0b11110000, 0, 0),
+            &ButtonState::Idle,
+        );
+    }
+}
+
+/// Candle light on every led, each one flickering in its own way
+pub fn candles(board: &mut impl AnimationTarget, small_rng: &mut SmallRng) {
+    for i in 0..16 {
+        let seed = small_rng.next_u32();
+        board.add_led_state(
+            i,
+            0,
+            flicker(Colour::rgb(0xff, 0x90, 0x20), 0xa0, 12, seed, 0, 0),
+            &ButtonState::Idle,
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Control the soundboard from the command line.
+//!
+//! Commands use the same syntax as the text mode of the device (see `help`), for example:
+//! `soundboard-ctl --port /dev/ttyACM0 state add 3 idle fade_in 1f ff8000 500 slot=0 next=0`
+
+use std::env;
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use pico_soundboard::animations::{breathing, candles, loading_circle, rainbow, random_fades};
+use pico_soundboard::host::{Client, ClientError};
+use pico_soundboard::request::{Request, MAX_BATCH_SIZE};
+use pico_soundboard::serial_protocol::ProtocolVersion;
+use pico_soundboard::text_protocol::{
+    button_state_from_name, parse_colour, parse_line, TextCommand, HELP,
+};
+use pico_soundboard::{ButtonState, Colour};
+use rand::{rngs::SmallRng, SeedableRng};
+use serialport::SerialPort;
+
+const DEFAULT_PORT: &str = "/dev/ttyACM0";
+
+/// How long a single read from the port may block, the response timeout is handled by `Client`
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+const USAGE: &str = "usage: soundboard-ctl [--port <path>] [--v2] <command>\n\
+    \n\
+    options:\n\
+    \x20 --port <path>  serial port of the device, $SOUNDBOARD_PORT or /dev/ttyACM0 by default\n\
+    \x20 --v2           switch to protocol v2 (with checksums) before sending the command\n\
+    \n\
+    additional commands:\n\
+    \x20 info\n\
+    \x20 animate loading-circle [<rrggbb> [<speed ms>]]\n\
+    \x20 animate breathing <led> idle|pressed [<rrggbb> [<speed ms>]]\n\
+    \x20 animate random-fades\n\
+    \x20 animate rainbow [<period ms>]\n\
+    \x20 animate candles\n\
+    \n\
+    Animations replace the state queues they use. Every queue is cleared and filled in the same\n\
+    batch, larger animations (e.g. random-fades) take several batches - if one of them fails, the\n\
+    queues sent before it keep the new states and the others keep the old ones.\n\
+    \n";
+
+enum Action {
+    Send(Request),
+    Info,
+    Animate(Vec<Request>),
+}
+
+fn main() -> ExitCode {
+    match run(env::args().skip(1).collect()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Vec<String>) -> Result<(), String> {
+    let mut port = env::var("SOUNDBOARD_PORT").unwrap_or_else(|_| DEFAULT_PORT.to_string());
+    let mut v2 = false;
+    let mut args = args.into_iter().peekable();
+    while let Some(option) = args.next_if(|arg| arg.starts_with("--")) {
+        match option.as_str() {
+            "--port" => port = args.next().ok_or("missing value of `--port`")?,
+            "--v2" => v2 = true,
+            "--help" => {
+                print!("{}{}", USAGE, HELP);
+                return Ok(());
+            }
+            _ => return Err(format!("unknown option `{}`", option)),
+        }
+    }
+    let command: Vec<String> = args.collect();
+
+    // Parse the command before opening the port, so that mistakes are reported without a device
+    let action = match command.first().map(String::as_str) {
+        None => return Err(format!("missing command\n{}", USAGE)),
+        Some("info") => Action::Info,
+        Some("animate") => Action::Animate(parse_animation(&command[1..])?),
+        Some(_) => match parse_line(&command.join(" ")).map_err(|error| error.to_string())? {
+            TextCommand::Request(request) => Action::Send(request),
+            TextCommand::Help => {
+                print!("{}{}", USAGE, HELP);
+                return Ok(());
+            }
+            TextCommand::Binary => return Err("`binary` is only available in the text mode".into()),
+        },
+    };
+
+    let mut client = open(&port)?;
+    if v2 {
+        client.handshake(ProtocolVersion::V2).map_err(describe)?;
+    }
+    match action {
+        Action::Send(request) => client.send(&request).map_err(describe),
+        Action::Info => {
+            let info = client.device_info().map_err(describe)?;
+            let (major, minor, patch) = info.firmware_version;
+            println!("firmware version: {}.{}.{}", major, minor, patch);
+            println!(
+                "protocol version: {} (up to {})",
+                info.protocol_version as u8, info.max_protocol_version
+            );
+            println!("buttons: {}", info.button_count);
+            println!("leds: {}", info.led_count);
+            println!("states per led: {}", info.queue_size);
+            println!("capabilities: {:#010x}", info.capabilities);
+            let transitions: Vec<&str> = info.transitions.iter().map(|kind| kind.name()).collect();
+            println!("transitions: {}", transitions.join(", "));
+            Ok(())
+        }
+        Action::Animate(requests) => send_animation(&mut client, requests).map_err(describe),
+    }
+}
+
+fn open(port: &str) -> Result<Client<Box<dyn SerialPort>>, String> {
+    // The baud rate is ignored by the USB serial device
+    let mut serial = serialport::new(port, 115_200)
+        .timeout(READ_TIMEOUT)
+        .open()
+        .map_err(|error| format!("cannot open {}: {}", port, error))?;
+    // The device waits for DTR before handling any messages
+    serial
+        .write_data_terminal_ready(true)
+        .map_err(|error| format!("cannot open {}: {}", port, error))?;
+    Ok(Client::new(serial))
+}
+
+fn describe(error: ClientError) -> String {
+    error.to_string()
+}
+
+fn parse_animation(args: &[String]) -> Result<Vec<Request>, String> {
+    let mut args = args.iter().map(String::as_str);
+    let mut requests = Vec::new();
+    match args.next() {
+        Some("loading-circle") => {
+            let colour = parse_optional_colour(args.next(), Colour::rgb(0x50, 0x00, 0x50))?;
+            let speed = parse_optional_speed(args.next())?;
+            loading_circle(&mut requests, colour, speed);
+        }
+        Some("breathing") => {
+            let led_idx = args
+                .next()
+                .and_then(|led| led.parse().ok())
+                .ok_or("missing or invalid led index")?;
+            let state = args
+                .next()
+                .and_then(button_state_from_name)
+                .ok_or("missing or invalid button state")?;
+            let colour = parse_optional_colour(args.next(), Colour::white())?;
+            let speed = parse_optional_speed(args.next())?;
+            breathing(&mut requests, led_idx, &state, colour, speed);
+        }
+        Some("random-fades") => random_fades(&mut requests, &mut time_seeded_rng()),
+        Some("rainbow") => {
+            let period = match args.next() {
+                Some(value) => value
+                    .parse()
+                    .map_err(|_| format!("invalid period `{}`", value))?,
+                None => 6000,
+            };
+            rainbow(&mut requests, period);
+        }
+        Some("candles") => candles(&mut requests, &mut time_seeded_rng()),
+        Some(name) => return Err(format!("unknown animation `{}`", name)),
+        None => return Err("missing animation name".into()),
+    }
+    if let Some(arg) = args.next() {
+        return Err(format!("unexpected argument `{}`", arg));
+    }
+    Ok(requests)
+}
+
+fn time_seeded_rng() -> SmallRng {
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64);
+    SmallRng::seed_from_u64(seed)
+}
+
+fn parse_optional_colour(value: Option<&str>, default: Colour) -> Result<Colour, String> {
+    match value {
+        Some(value) => parse_colour(value).ok_or(format!("invalid colour `{}`", value)),
+        None => Ok(default),
+    }
+}
+
+fn parse_optional_speed(value: Option<&str>) -> Result<usize, String> {
+    match value {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("invalid speed `{}`", value)),
+        None => Ok(100),
+    }
+}
+
+/// Replace the state queues used by the animation. The requests are staged in batches, so that
+/// leds start animating at the same time - or in groups, if there are more states than fit in
+/// one batch. A queue is always cleared and filled in the same batch, so it never runs a mix of
+/// the old and the new states, even if a later batch fails.
+fn send_animation(
+    client: &mut Client<Box<dyn SerialPort>>,
+    requests: Vec<Request>,
+) -> Result<(), ClientError> {
+    let mut queues: Vec<((usize, ButtonState), Vec<Request>)> = Vec::new();
+    for request in requests {
+        let Request::AddState {
+            led_idx, for_state, ..
+        } = request
+        else {
+            continue;
+        };
+        let queue = match queues
+            .iter()
+            .position(|(key, _)| *key == (led_idx, for_state))
+        {
+            Some(position) => &mut queues[position].1,
+            None => {
+                let clear = Request::ClearStates { led_idx, for_state };
+                queues.push(((led_idx, for_state), vec![clear]));
+                &mut queues.last_mut().unwrap().1
+            }
+        };
+        queue.push(request);
+    }
+    let mut batches: Vec<Vec<Request>> = Vec::new();
+    for (_, queue) in queues {
+        match batches.last_mut() {
+            Some(batch) if batch.len() + queue.len() <= MAX_BATCH_SIZE => batch.extend(queue),
+            _ => batches.push(queue),
+        }
+    }
+
+    for batch in batches {
+        client.begin_batch()?;
+        for request in &batch {
+            if let Err(error) = client.send(request) {
+                let _ = client.abort_batch();
+                return Err(error);
+            }
+        }
+        client.commit_batch()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,279 @@
+//! Run the firmware on the host, without the board.
+//!
+//! The real `Board` and serial loop are driven by mocked I2C and SPI buses: buttons are pressed
+//! by typing commands, the leds are drawn in the terminal and the serial protocol is served on a
+//! pseudo terminal, e.g. `soundboard-ctl --port /dev/pts/3 info`.
+
+use std::cell::RefCell;
+use std::io::{self, BufRead, ErrorKind, Read, Write};
+use std::process::ExitCode;
+use std::thread;
+use std::time::Duration;
+
+use embassy_futures::join::join3;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Ticker, Timer};
+use heapless::Vec;
+use pico_soundboard::animations::loading_circle;
+use pico_soundboard::board::Board;
+use pico_soundboard::mock::{block_on, MockI2c, MockLeds, MockSpi};
+use pico_soundboard::rgbleds::LedOutput;
+use pico_soundboard::serial_connection::{
+    apply_connection_defaults, forward_button_events, serial_loop, ResetRequested, SerialTransport,
+    SharedBoard,
+};
+use pico_soundboard::{ButtonState, Colour, LED_COUNT};
+use serialport::{SerialPort, TTYPort};
+
+static SERIAL_INPUT: Channel<CriticalSectionRawMutex, PtyInput, 16> = Channel::new();
+
+/// How long a `tap` holds the button down
+const TAP_DURATION: Duration = Duration::from_millis(150);
+
+const RENDER_INTERVAL: Duration = Duration::from_millis(33);
+
+/// Lines at the top of the terminal reserved for the leds, the rest scrolls below them
+const GRID_LINES: usize = 5;
+
+const USAGE: &str = "commands:\n\
+    \x20 <led> | tap <led>    press and release a button\n\
+    \x20 press <led>          hold a button down\n\
+    \x20 release <led>        let a button go\n\
+    \x20 quit\n";
+
+fn main() -> ExitCode {
+    let (master, slave) = match TTYPort::pair() {
+        Ok(pair) => pair,
+        Err(error) => {
+            eprintln!("error: cannot create a pseudo terminal: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+    let port = slave.name().unwrap_or_default();
+    // Closing the slave lets the reader notice when a host connects and disconnects
+    drop(slave);
+    let reader = match master.try_clone_native() {
+        Ok(reader) => reader,
+        Err(error) => {
+            eprintln!("error: cannot open the pseudo terminal: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Keep the leds on top and let everything else scroll below them
+    print!("\x1b[2J\x1b[{};r\x1b[{};1H", GRID_LINES + 1, GRID_LINES + 1);
+    println!("serial port: {}", port);
+    print!("{}", USAGE);
+    let _ = io::stdout().flush();
+
+    let buttons = MockI2c::new();
+    let spi = MockSpi::new();
+    let leds = spi.leds();
+    thread::spawn(move || read_pty(reader));
+    thread::spawn({
+        let buttons = buttons.clone();
+        move || read_commands(buttons)
+    });
+    thread::spawn(move || render(leds));
+
+    block_on(run(master, buttons, spi));
+    ExitCode::SUCCESS
+}
+
+async fn run(master: TTYPort, buttons: MockI2c, spi: MockSpi) {
+    // Clones share the buttons, so the board can be rebuilt on reset
+    let board: SharedBoard<_> = Mutex::new(RefCell::new(boot(&buttons)));
+    let mut leds = LedOutput::new(spi).await;
+    let mut transport = PtyTransport {
+        master,
+        pending: None,
+    };
+
+    let rgb_fut = async {
+        let mut ticker = Ticker::every(embassy_time::Duration::from_millis(1));
+        loop {
+            leds.refresh(&board).await;
+            ticker.next().await;
+        }
+    };
+
+    let in_fut = async {
+        loop {
+            {
+                let mut _board = board.lock().await;
+                _board.get_mut().update_status().await.unwrap();
+                forward_button_events(_board.get_mut());
+            }
+            Timer::after_millis(1).await;
+        }
+    };
+
+    let serial_fut = async {
+        loop {
+            transport.wait_connection().await;
+            println!("host connected");
+            apply_connection_defaults(board.lock().await.get_mut());
+            match serial_loop(&mut transport, &board).await {
+                Ok(ResetRequested) => {
+                    println!("resetting the device");
+                    *board.lock().await.get_mut() = boot(&buttons);
+                }
+                Err(Disconnected) => println!("host disconnected"),
+            }
+        }
+    };
+
+    join3(rgb_fut, in_fut, serial_fut).await;
+}
+
+/// Same start up as the firmware - a loading circle until the host connects
+fn boot(buttons: &MockI2c) -> Board<MockI2c> {
+    let mut board = Board::new(buttons.clone());
+    board.lock_led_states(&ButtonState::Idle);
+    loading_circle(&mut board, Colour::rgb(0x50, 0x0, 0x50), 100);
+    board
+}
+
+enum PtyInput {
+    Packet(Vec<u8, 64>),
+    Closed,
+}
+
+struct Disconnected;
+
+struct PtyTransport {
+    master: TTYPort,
+    // First packet of a connection, received while waiting for it
+    pending: Option<Vec<u8, 64>>,
+}
+
+impl PtyTransport {
+    /// There is no DTR on a pseudo terminal, a host is connected once it sends something
+    async fn wait_connection(&mut self) {
+        while self.pending.is_none() {
+            if let PtyInput::Packet(packet) = SERIAL_INPUT.receive().await {
+                self.pending = Some(packet);
+            }
+        }
+    }
+}
+
+impl SerialTransport for PtyTransport {
+    type Error = Disconnected;
+
+    async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, Disconnected> {
+        let packet = match self.pending.take() {
+            Some(packet) => packet,
+            None => match SERIAL_INPUT.receive().await {
+                PtyInput::Packet(packet) => packet,
+                PtyInput::Closed => return Err(Disconnected),
+            },
+        };
+        data[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), Disconnected> {
+        self.master.write_all(data).map_err(|_| Disconnected)
+    }
+}
+
+/// Pass everything written by the host to the serial loop in packets of at most 64 bytes
+fn read_pty(mut reader: TTYPort) {
+    let _ = reader.set_timeout(Duration::from_millis(100));
+    let mut connected = false;
+    let mut buffer = [0; 64];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => {}
+            Ok(n) => {
+                connected = true;
+                let packet = Vec::from_slice(&buffer[..n]).unwrap();
+                // Blocks the reader like a full USB endpoint would block the host
+                while SERIAL_INPUT
+                    .try_send(PtyInput::Packet(packet.clone()))
+                    .is_err()
+                {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+            Err(error) if error.kind() == ErrorKind::TimedOut => {}
+            // Reading fails while the slave side is not open
+            Err(_) => {
+                if connected {
+                    connected = false;
+                    while SERIAL_INPUT.try_send(PtyInput::Closed).is_err() {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+}
+
+fn read_commands(buttons: MockI2c) {
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let args: std::vec::Vec<&str> = line.split_whitespace().collect();
+        let (command, led) = match args.as_slice() {
+            [] => continue,
+            ["quit"] | ["q"] => {
+                // Restore the scroll region
+                print!("\x1b[r\x1b[999;1H");
+                let _ = io::stdout().flush();
+                std::process::exit(0);
+            }
+            [led] => ("tap", *led),
+            [command, led] => (*command, *led),
+            _ => {
+                print!("{}", USAGE);
+                continue;
+            }
+        };
+        let Some(led) = led.parse::<usize>().ok().filter(|&led| led < LED_COUNT) else {
+            println!("invalid led `{}`", led);
+            continue;
+        };
+        match command {
+            "press" | "p" => buttons.press(led),
+            "release" | "r" => buttons.release(led),
+            "tap" | "t" => {
+                buttons.press(led);
+                thread::sleep(TAP_DURATION);
+                buttons.release(led);
+            }
+            _ => print!("{}", USAGE),
+        }
+    }
+}
+
+/// Draw the leds as a 4x4 grid, numbered the same way as in the protocol
+fn render(leds: MockLeds) {
+    let mut drawn = None;
+    loop {
+        let frame = leds.frame();
+        if drawn != Some(frame) {
+            drawn = Some(frame);
+            let mut out = std::string::String::from("\x1b7\x1b[1;1H");
+            for row in frame.chunks(4) {
+                for led in row {
+                    let (red, green, blue) = led.scaled();
+                    out.push_str(&format!(
+                        "\x1b[48;2;{};{};{}m      \x1b[0m ",
+                        red, green, blue
+                    ));
+                }
+                out.push_str("\x1b[K\n");
+            }
+            out.push_str("\x1b8");
+            print!("{}", out);
+            let _ = io::stdout().flush();
+        }
+        thread::sleep(RENDER_INTERVAL);
+    }
+}
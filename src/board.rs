@@ -1,43 +1,53 @@
 extern crate alloc;
 
 use alloc::boxed::Box;
-use embedded_hal_async::{i2c::I2c, spi::SpiBus};
-use heapless::Vec;
+use defmt::Format;
+use embassy_time::Instant;
+use embedded_hal_async::i2c::I2c;
+use heapless::{Deque, Vec};
 
 use crate::{
-    rgbleds::RGBLeds, transitions::TransitionFunction, Button, ButtonCode, ButtonState, Colour,
+    calibration::Calibration,
+    flow::{Flag, Flow},
+    rgbleds::{LedFrame, LedStatus, RGBLeds},
+    transitions::Transition,
+    Button, ButtonCode, ButtonState, Colour,
 };
 
-type ButtonCallback<I2C, SPI> = Option<Box<dyn Fn(&mut Board<I2C, SPI>) -> ButtonCallbackResult>>;
+type ButtonCallback<I2C> = Option<Box<dyn Fn(&mut Board<I2C>) -> ButtonCallbackResult>>;
 
 pub enum ButtonCallbackResult {
     Remove,
     Keep,
 }
 
-pub struct Board<I2C, SPI> {
+/// Button press or release, registered by [`Board::update_status`]
+#[derive(Format, Clone, Copy)]
+pub struct ButtonEvent {
+    pub led_idx: u8,
+    pub state: ButtonState,
+    pub timestamp_ms: u64,
+}
+
+const BUTTON_EVENT_QUEUE_SIZE: usize = 32;
+
+pub struct Board<I2C> {
     i2c: I2C,
     buttons: [Button; 16],
-    callbacks_pressed: Vec<ButtonCallback<I2C, SPI>, 16>,
-    callbacks_released: Vec<ButtonCallback<I2C, SPI>, 16>,
-    rgb_leds: RGBLeds<SPI>,
+    callbacks_pressed: Vec<ButtonCallback<I2C>, 16>,
+    callbacks_released: Vec<ButtonCallback<I2C>, 16>,
+    rgb_leds: RGBLeds,
     keyboard_input_enabled: bool,
+    events: Deque<ButtonEvent, BUTTON_EVENT_QUEUE_SIZE>,
 }
 
-impl<I2C: I2c, SPI: SpiBus> Board<I2C, SPI> {
-    pub async fn new(i2c: I2C, spi: SPI) -> Self {
+impl<I2C: I2c> Board<I2C> {
+    pub fn new(i2c: I2C) -> Self {
         let buttons = core::array::from_fn(|i| Button::new(ButtonCode::try_from(1 << i).unwrap()));
-        let mut rgb_leds = RGBLeds::new(spi);
-        // Needed for initialisation
-        rgb_leds.full(0xff, Colour::white());
-        rgb_leds.refresh().await;
-        rgb_leds.full(0xff, Colour::rgb(0x0, 0x0, 0x0));
-        rgb_leds.refresh().await;
-        rgb_leds.clear_all();
-        rgb_leds.refresh().await;
-
-        let mut callbacks_pressed: Vec<ButtonCallback<I2C, SPI>, 16> = Vec::new();
-        let mut callbacks_released: Vec<ButtonCallback<I2C, SPI>, 16> = Vec::new();
+        let rgb_leds = RGBLeds::new();
+
+        let mut callbacks_pressed: Vec<ButtonCallback<I2C>, 16> = Vec::new();
+        let mut callbacks_released: Vec<ButtonCallback<I2C>, 16> = Vec::new();
 
         (0..16).for_each(|_| {
             let _ = callbacks_pressed.push(None);
@@ -51,10 +61,11 @@ impl<I2C: I2c, SPI: SpiBus> Board<I2C, SPI> {
             callbacks_pressed,
             callbacks_released,
             keyboard_input_enabled: false,
+            events: Deque::new(),
         }
     }
 
-    pub fn add_callback_pressed(&mut self, button_idx: usize, callback: ButtonCallback<I2C, SPI>) {
+    pub fn add_callback_pressed(&mut self, button_idx: usize, callback: ButtonCallback<I2C>) {
         self.callbacks_pressed[map_idx_from_button_to_led(button_idx)] = callback;
     }
 
@@ -62,7 +73,7 @@ impl<I2C: I2c, SPI: SpiBus> Board<I2C, SPI> {
         self.callbacks_pressed[map_idx_from_button_to_led(button_idx)] = None;
     }
 
-    pub fn add_callback_released(&mut self, button_idx: usize, callback: ButtonCallback<I2C, SPI>) {
+    pub fn add_callback_released(&mut self, button_idx: usize, callback: ButtonCallback<I2C>) {
         self.callbacks_released[map_idx_from_button_to_led(button_idx)] = callback;
     }
 
@@ -78,11 +89,32 @@ impl<I2C: I2c, SPI: SpiBus> Board<I2C, SPI> {
         self.keyboard_input_enabled = true;
     }
 
+    pub fn keyboard_input_enabled(&self) -> bool {
+        self.keyboard_input_enabled
+    }
+
+    /// Take the oldest button event registered by `update_status`
+    pub fn pop_event(&mut self) -> Option<ButtonEvent> {
+        self.events.pop_front()
+    }
+
+    fn push_event(&mut self, led_idx: usize, state: ButtonState) {
+        // Drop the oldest event if nobody is reading them
+        if self.events.is_full() {
+            self.events.pop_front();
+        }
+        let _ = self.events.push_back(ButtonEvent {
+            led_idx: led_idx as u8,
+            state,
+            timestamp_ms: Instant::now().as_millis(),
+        });
+    }
+
     pub fn add_led_state(
         &mut self,
         led_idx: usize,
         state_idx: usize,
-        transition: TransitionFunction,
+        transition: Transition,
         for_state: &ButtonState,
     ) {
         self.rgb_leds
@@ -93,8 +125,65 @@ impl<I2C: I2c, SPI: SpiBus> Board<I2C, SPI> {
         self.rgb_leds.remove_state(led_idx, state_idx, for_state);
     }
 
-    pub async fn refresh_leds(&mut self) {
-        self.rgb_leds.refresh().await;
+    /// Repeat count and branch of a slot, see [`Flow`]
+    pub fn set_led_state_flow(
+        &mut self,
+        led_idx: usize,
+        state_idx: usize,
+        flow: Flow,
+        for_state: &ButtonState,
+    ) {
+        self.rgb_leds
+            .set_state_flow(led_idx, state_idx, flow, for_state);
+    }
+
+    /// Flags checked by the branches of the state queues of all leds
+    pub fn set_flag(&mut self, flag: Flag, value: bool) {
+        self.rgb_leds.set_flag(flag, value);
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.rgb_leds.flags()
+    }
+
+    /// Advance the led states and write them to the frame sent by a
+    /// [`LedOutput`](crate::rgbleds::LedOutput)
+    pub fn render_leds(&mut self, frame: &mut LedFrame) {
+        self.rgb_leds.render(frame);
+    }
+
+    /// Correction applied to the led states on every refresh, see [`Calibration`]
+    pub fn calibration(&self) -> &Calibration {
+        self.rgb_leds.calibration()
+    }
+
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        *self.rgb_leds.calibration_mut() = calibration;
+    }
+
+    /// Gamma of the red, green and blue channels in tenths
+    pub fn set_gamma(&mut self, gamma: [u8; 3]) {
+        self.rgb_leds.calibration_mut().set_gamma(gamma);
+    }
+
+    pub fn set_colour_correction(&mut self, led_idx: usize, correction: Colour) {
+        self.rgb_leds
+            .calibration_mut()
+            .set_colour_correction(led_idx, correction);
+    }
+
+    pub fn set_colour_corrections(&mut self, correction: Colour) {
+        for i in 0..16 {
+            self.set_colour_correction(i, correction);
+        }
+    }
+
+    pub fn set_global_brightness(&mut self, brightness: u8) {
+        self.rgb_leds.calibration_mut().set_brightness(brightness);
+    }
+
+    pub fn set_dithering(&mut self, dithering: bool) {
+        self.rgb_leds.calibration_mut().set_dithering(dithering);
     }
 
     pub fn lock_led_states(&mut self, state: &ButtonState) {
@@ -126,6 +215,18 @@ impl<I2C: I2c, SPI: SpiBus> Board<I2C, SPI> {
         self.rgb_leds.clear(index, states);
     }
 
+    pub fn led_status(&self, led_idx: usize) -> LedStatus {
+        self.rgb_leds.status(led_idx)
+    }
+
+    pub fn led_states(
+        &self,
+        led_idx: usize,
+        for_state: &ButtonState,
+    ) -> impl Iterator<Item = (usize, &Transition, &Flow)> {
+        self.rgb_leds.states(led_idx, for_state)
+    }
+
     // Return 6 first pressed keys (max supported by `usbd_hid`'s `KeyboardReport`)
     pub async fn update_status(&mut self) -> Result<[u8; 16], &str> {
         let mut i2c_read_buffer = [0u8; 2];
@@ -161,6 +262,7 @@ impl<I2C: I2c, SPI: SpiBus> Board<I2C, SPI> {
                                 ButtonState::Pressed,
                             );
                             self.buttons[i].pressed = true;
+                            self.push_event(map_idx_from_button_to_led(i), ButtonState::Pressed);
                             let callback = self.callbacks_pressed.get_mut(i).unwrap().take();
                             if callback.is_some() {
                                 let cb = callback.unwrap();
@@ -177,6 +279,7 @@ impl<I2C: I2c, SPI: SpiBus> Board<I2C, SPI> {
                             self.rgb_leds
                                 .set_button_state(map_idx_from_button_to_led(i), ButtonState::Idle);
                             self.buttons[i].pressed = false;
+                            self.push_event(map_idx_from_button_to_led(i), ButtonState::Idle);
                             let callback = self.callbacks_released.get_mut(i).unwrap().take();
                             if callback.is_some() {
                                 let cb = callback.unwrap();
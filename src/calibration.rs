@@ -0,0 +1,221 @@
+use crate::{
+    rgbleds::{LedState, MAX_LEVEL},
+    Colour, LED_COUNT,
+};
+
+/// Gamma values are given in tenths, e.g. `22` for 2.2
+pub const DEFAULT_GAMMA: u8 = 22;
+/// Gamma of 1.0 passes the values through unchanged
+pub const LINEAR_GAMMA: u8 = 10;
+pub const MAX_GAMMA: u8 = 40;
+
+/// Correction of the rendered led states before they are written to the leds, so that the
+/// perceived colours match the requested ones.
+///
+/// Each channel goes through its gamma lookup table, then it is scaled by the colour correction
+/// of the led (e.g. to balance the white point of a led with a stronger blue die), by the global
+/// brightness and finally by the level of the state. Factors of 255 leave the values unchanged.
+#[derive(Clone, Debug)]
+pub struct Calibration {
+    // Red, green and blue
+    gamma: [GammaTable; 3],
+    correction: [Colour; LED_COUNT],
+    brightness: u8,
+    dithering: bool,
+}
+
+impl Calibration {
+    /// Default gamma on all channels, without any colour correction and at full brightness
+    pub fn new() -> Self {
+        Self {
+            gamma: [
+                GammaTable::new(DEFAULT_GAMMA),
+                GammaTable::new(DEFAULT_GAMMA),
+                GammaTable::new(DEFAULT_GAMMA),
+            ],
+            correction: [Colour::white(); LED_COUNT],
+            brightness: 0xff,
+            dithering: false,
+        }
+    }
+
+    /// Pass the led states through unchanged
+    pub fn raw() -> Self {
+        let mut calibration = Self::new();
+        calibration.set_gamma([LINEAR_GAMMA; 3]);
+        calibration
+    }
+
+    /// Gamma of the red, green and blue channels in tenths, clamped to `1..=MAX_GAMMA`
+    pub fn set_gamma(&mut self, gamma: [u8; 3]) {
+        self.gamma
+            .iter_mut()
+            .zip(gamma)
+            .filter(|(table, gamma)| table.gamma() != *gamma)
+            .for_each(|(table, gamma)| *table = GammaTable::new(gamma));
+    }
+
+    pub fn gamma(&self) -> [u8; 3] {
+        core::array::from_fn(|i| self.gamma[i].gamma())
+    }
+
+    /// Factors of the red, green and blue channels of a single led
+    pub fn set_colour_correction(&mut self, led_idx: usize, correction: Colour) {
+        self.correction[led_idx % LED_COUNT] = correction;
+    }
+
+    pub fn colour_correction(&self, led_idx: usize) -> Colour {
+        self.correction[led_idx % LED_COUNT]
+    }
+
+    /// Scales all channels of all leds
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    /// Spread the steps between the values the leds can show over several refreshes, for
+    /// smooth fades at low brightness
+    pub fn set_dithering(&mut self, dithering: bool) {
+        self.dithering = dithering;
+    }
+
+    pub fn dithering(&self) -> bool {
+        self.dithering
+    }
+
+    /// Intensity of the red, green and blue channels of the led with the given index, out of
+    /// [`MAX_LEVEL`]
+    pub fn apply(&self, led_idx: usize, state: &LedState) -> [u16; 3] {
+        let correction = self.colour_correction(led_idx);
+        let [red, green, blue] = &self.gamma;
+        [
+            self.scale(red.apply(state.r), correction.red, state.level),
+            self.scale(green.apply(state.g), correction.green, state.level),
+            self.scale(blue.apply(state.b), correction.blue, state.level),
+        ]
+    }
+
+    fn scale(&self, value: u16, correction: u8, level: u16) -> u16 {
+        let divisor = 255 * 255 * MAX_LEVEL as u64;
+        ((value as u64 * correction as u64 * self.brightness as u64 * level as u64 + divisor / 2)
+            / divisor) as u16
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Maps a channel value `x` to `MAX_LEVEL * (x / 255) ^ gamma`, rounded. The output is finer
+/// than the input, so that dim colours do not collapse into the same few values.
+#[derive(Clone, Debug)]
+struct GammaTable {
+    gamma: u8,
+    table: [u16; 256],
+}
+
+impl GammaTable {
+    fn new(gamma: u8) -> Self {
+        let gamma = gamma.clamp(1, MAX_GAMMA);
+        // The leds have no FPU, so `(x / 255) ^ (gamma / 10)` is computed as
+        // `2 ^ -(gamma / 10 * -log2(x / 255))` in fixed point, with the logarithms looked up
+        let table = core::array::from_fn(|x| match x {
+            0 => 0,
+            x => {
+                let exponent = gamma as u64 * DIMMING[x] as u64 / LINEAR_GAMMA as u64;
+                let shift = exponent >> LOG_FRACTION_BITS;
+                if shift > 16 {
+                    return 0;
+                }
+                // `2 ^ -fraction` is the product of the roots of 1/2 of its set bits
+                let fraction = HALVINGS
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| exponent & (1 << (LOG_FRACTION_BITS - 1 - i)) != 0)
+                    .fold(1 << 32, |result, (_, factor)| (result * factor) >> 32);
+                ((((MAX_LEVEL as u64 * fraction) >> shift) + (1 << 31)) >> 32) as u16
+            }
+        });
+        Self { gamma, table }
+    }
+
+    fn gamma(&self) -> u8 {
+        self.gamma
+    }
+
+    fn apply(&self, value: u8) -> u16 {
+        self.table[value as usize]
+    }
+}
+
+/// Fraction bits of the fixed point logarithms
+const LOG_FRACTION_BITS: usize = 24;
+
+/// `-log2(x / 255)` of every channel value, with `LOG_FRACTION_BITS` fraction bits. Unused for 0.
+const DIMMING: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut x = 1;
+    while x < 256 {
+        table[x] = (log2(255) - log2(x as u32)) as u32;
+        x += 1;
+    }
+    table
+};
+
+/// `2 ^ -(2 ^ -(i + 1))` with 32 fraction bits, the factor of the `i`th fraction bit of a
+/// logarithm, starting from the highest. Each one is the square root of the previous one.
+const HALVINGS: [u64; LOG_FRACTION_BITS] = {
+    let mut table = [0; LOG_FRACTION_BITS];
+    let mut root = 1 << 31;
+    let mut i = 0;
+    while i < LOG_FRACTION_BITS {
+        root = isqrt(root << 32);
+        table[i] = root;
+        i += 1;
+    }
+    table
+};
+
+/// Base 2 logarithm of `x` with `LOG_FRACTION_BITS` fraction bits, rounded down
+const fn log2(x: u32) -> u64 {
+    let integer = 31 - x.leading_zeros();
+    let mut result = (integer as u64) << LOG_FRACTION_BITS;
+    // Squaring the mantissa (between 1 and 2, with 30 fraction bits) doubles its logarithm, so
+    // every time it reaches 2 the next bit of the result is set
+    let mut mantissa = (x as u64) << (30 - integer);
+    let mut bit = 1 << (LOG_FRACTION_BITS - 1);
+    while bit != 0 {
+        mantissa = (mantissa * mantissa) >> 30;
+        if mantissa >= 2 << 30 {
+            mantissa >>= 1;
+            result |= bit;
+        }
+        bit >>= 1;
+    }
+    result
+}
+
+/// Square root, rounded down
+const fn isqrt(mut value: u64) -> u64 {
+    let mut root = 0;
+    let mut bit = 1 << 62;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if value >= root + bit {
+            value -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
@@ -0,0 +1,146 @@
+use crate::{serial_protocol::ParseError, ButtonState};
+
+/// Flags are the bits of a byte
+pub const FLAG_COUNT: u8 = 8;
+
+/// Index of a flag set by the host, checked once when it is created so that it always selects a
+/// bit of the flags byte
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Flag(u8);
+
+impl Flag {
+    /// `None` for indices from [`FLAG_COUNT`] on
+    pub fn new(index: u8) -> Option<Self> {
+        (index < FLAG_COUNT).then_some(Flag(index))
+    }
+
+    pub fn index(&self) -> u8 {
+        self.0
+    }
+
+    /// The flag in the flags byte
+    pub fn bit(&self) -> u8 {
+        1 << self.0
+    }
+}
+
+/// How the state queue moves on once the state in a slot is finished, set with `SetStateFlow`.
+///
+/// The state is played `repeat_count` times first (`0` plays it once, like `1`). Then, if the
+/// condition of the branch holds, the queue jumps to the slot of the branch, otherwise to the
+/// next state of the transition. States that never finish never move on, so they never branch.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Flow {
+    pub repeat_count: u8,
+    pub branch: Option<Branch>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Branch {
+    pub condition: Condition,
+    pub target: usize,
+}
+
+/// Condition of a [`Branch`], checked every time the state finishes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Condition {
+    /// The button has been held for at least this many ticks
+    HeldFor(u16),
+    /// The button has been pressed at least this many times since the last branch of the led
+    /// was taken
+    Presses(u16),
+    /// The flag set by the host is on
+    FlagSet(Flag),
+    /// The flag set by the host is off
+    FlagClear(Flag),
+}
+
+/// Everything the conditions of the branches depend on
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FlowInputs {
+    pub held_ticks: usize,
+    pub press_count: usize,
+    pub flags: u8,
+}
+
+impl Condition {
+    pub fn holds(&self, inputs: &FlowInputs) -> bool {
+        match *self {
+            Condition::HeldFor(ticks) => inputs.held_ticks >= ticks as usize,
+            Condition::Presses(count) => inputs.press_count >= count as usize,
+            Condition::FlagSet(flag) => inputs.flags & flag.bit() != 0,
+            Condition::FlagClear(flag) => inputs.flags & flag.bit() == 0,
+        }
+    }
+
+    /// Code and value sent in `SetStateFlow`, `0` is no condition
+    fn to_code(self) -> (u8, u16) {
+        match self {
+            Condition::HeldFor(ticks) => (1, ticks),
+            Condition::Presses(count) => (2, count),
+            Condition::FlagSet(flag) => (3, flag.index() as u16),
+            Condition::FlagClear(flag) => (4, flag.index() as u16),
+        }
+    }
+
+    fn from_code(code: u8, value: u16) -> Result<Option<Self>, ParseError> {
+        let flag = || {
+            u8::try_from(value)
+                .ok()
+                .and_then(Flag::new)
+                .ok_or(ParseError::InvalidData)
+        };
+        match code {
+            0 => Ok(None),
+            1 => Ok(Some(Condition::HeldFor(value))),
+            2 => Ok(Some(Condition::Presses(value))),
+            3 => Ok(Some(Condition::FlagSet(flag()?))),
+            4 => Ok(Some(Condition::FlagClear(flag()?))),
+            _ => Err(ParseError::InvalidData),
+        }
+    }
+}
+
+impl Flow {
+    /// Data of the `SetStateFlow` message setting the flow of a slot
+    pub fn to_data(&self, led_idx: usize, for_state: &ButtonState, state_idx: usize) -> [u8; 8] {
+        let (target, (code, value)) = match self.branch {
+            Some(branch) => (branch.target as u8, branch.condition.to_code()),
+            None => (0, (0, 0)),
+        };
+        let [high, low] = value.to_be_bytes();
+        [
+            (*for_state as u8) << 7 | (led_idx as u8 & 0b00001111),
+            (state_idx as u8) << 4 | (target & 0b00001111),
+            self.repeat_count,
+            code,
+            high,
+            low,
+            0,
+            0,
+        ]
+    }
+
+    /// Inverse of [`Flow::to_data`], the led and the slot are left to the caller. The bytes that
+    /// [`Flow::to_data`] leaves at 0 are rejected otherwise, as are a branch target and a value
+    /// without a condition.
+    pub fn from_data(data: &[u8; 8]) -> Result<Self, ParseError> {
+        let target = data[1] & 0b00001111;
+        let value = u16::from_be_bytes([data[4], data[5]]);
+        if data[6..] != [0, 0] {
+            return Err(ParseError::InvalidData);
+        }
+        let branch = match Condition::from_code(data[3], value)? {
+            Some(condition) => Some(Branch {
+                condition,
+                target: target as usize,
+            }),
+            None if target == 0 && value == 0 => None,
+            None => return Err(ParseError::InvalidData),
+        };
+        Ok(Flow {
+            repeat_count: data[2],
+            branch,
+        })
+    }
+}
@@ -0,0 +1,658 @@
+//! Host side of the serial protocol, available with the `std` feature.
+//!
+//! [`Client`] sends [`Request`]s over any `Read + Write` transport (a serial port, a pty or an
+//! in-memory pipe) and waits for the matching response.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    animations::AnimationTarget,
+    board::ButtonEvent,
+    flow::{Flag, Flow},
+    request::Request,
+    rgbleds::{LedStatus, LED_STATE_QUEUE_SIZE},
+    serial_protocol::{
+        Capability, DeviceError, Framer, Framing, MessageAssembler, NackType, ParseError,
+        ProtocolVersion, SerialCommand, SerialMessage, SyncRecord, SYNC_UNLOCKED,
+    },
+    transitions::{Transition, TransitionKind},
+    ButtonState, Colour, LED_COUNT,
+};
+
+/// Time to wait for a response, see "Timing rules" in the README
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Delay between reads while the transport has no data
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Type of a `NACK`, with the reason sent by the device if it is a known one
+#[derive(Debug, Clone, Copy)]
+pub enum Nack {
+    General,
+    InvalidCommand,
+    ParseError(Option<ParseError>),
+    DeviceError(Option<DeviceError>),
+    DeviceBusy,
+}
+
+impl fmt::Display for Nack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Nack::General => write!(f, "{}", NackType::General.description()),
+            Nack::InvalidCommand => write!(f, "{}", NackType::InvalidCommand.description()),
+            Nack::ParseError(error) => {
+                write!(f, "{}", NackType::NackParseError.description())?;
+                match error {
+                    Some(error) => write!(f, ": {}", error.description()),
+                    None => Ok(()),
+                }
+            }
+            Nack::DeviceError(error) => {
+                write!(f, "{}", NackType::DeviceError.description())?;
+                match error {
+                    Some(error) => write!(f, ": {}", error.description()),
+                    None => Ok(()),
+                }
+            }
+            Nack::DeviceBusy => write!(f, "{}", NackType::DeviceBusy.description()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    /// No response in the timeout
+    Timeout,
+    /// The device rejected the request
+    Nack(Nack),
+    /// The request was not sent, because the device would reject it
+    InvalidRequest(DeviceError),
+    /// The device sent something that is not a valid response to the request
+    InvalidResponse,
+}
+
+impl From<io::Error> for ClientError {
+    fn from(value: io::Error) -> Self {
+        ClientError::Io(value)
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(error) => write!(f, "I/O error: {}", error),
+            ClientError::Timeout => write!(f, "no response from the device"),
+            ClientError::Nack(nack) => write!(f, "rejected by the device: {}", nack),
+            ClientError::InvalidRequest(error) => write!(f, "{}", error.description()),
+            ClientError::InvalidResponse => write!(f, "invalid response from the device"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Response to `DeviceInfo`
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub firmware_version: (u8, u8, u8),
+    pub protocol_version: ProtocolVersion,
+    pub max_protocol_version: u8,
+    pub button_count: u8,
+    pub led_count: u8,
+    pub queue_size: u8,
+    pub capabilities: u32,
+    /// Supported transitions known to this version of the client
+    pub transitions: Vec<TransitionKind>,
+}
+
+impl DeviceInfo {
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.capabilities & 1 << capability as u8 != 0
+    }
+}
+
+impl TryFrom<&[u8]> for DeviceInfo {
+    type Error = ClientError;
+
+    fn try_from(payload: &[u8]) -> Result<Self, Self::Error> {
+        if payload.len() < 13 || payload.len() < 13 + payload[12] as usize {
+            return Err(ClientError::InvalidResponse);
+        }
+        Ok(DeviceInfo {
+            firmware_version: (payload[0], payload[1], payload[2]),
+            protocol_version: ProtocolVersion::try_from(payload[3])
+                .map_err(|_| ClientError::InvalidResponse)?,
+            max_protocol_version: payload[4],
+            button_count: payload[5],
+            led_count: payload[6],
+            queue_size: payload[7],
+            capabilities: u32::from_be_bytes([payload[8], payload[9], payload[10], payload[11]]),
+            transitions: payload[13..13 + payload[12] as usize]
+                .iter()
+                .filter_map(|kind| TransitionKind::try_from(*kind).ok())
+                .collect(),
+        })
+    }
+}
+
+/// Device state streamed in response to `SyncRequest`
+#[derive(Clone)]
+pub struct SyncState {
+    pub keyboard_input_enabled: bool,
+    pub flags: u8,
+    pub leds: Vec<SyncedLed>,
+}
+
+#[derive(Clone)]
+pub struct SyncedLed {
+    pub led_idx: usize,
+    pub status: LedStatus,
+    /// Occupied slots of both state queues
+    pub states: Vec<SyncedState>,
+}
+
+#[derive(Clone)]
+pub struct SyncedState {
+    pub for_state: ButtonState,
+    pub state_idx: usize,
+    pub transition: Transition,
+    pub flow: Flow,
+}
+
+/// Client of the serial protocol.
+///
+/// `read` of the transport should not block for longer than the response timeout - it may
+/// return no data or a `WouldBlock`/`TimedOut` error instead, e.g. a serial port with a read
+/// timeout or a non-blocking pty.
+pub struct Client<T: Read + Write> {
+    transport: T,
+    framing: Framing,
+    framer: Framer,
+    sequence: u8,
+    timeout: Duration,
+    // Button events received while waiting for responses
+    events: VecDeque<ButtonEvent>,
+}
+
+impl<T: Read + Write> Client<T> {
+    /// Every connection starts with protocol v1, see [`Client::handshake`]
+    pub fn new(transport: T) -> Self {
+        Client {
+            transport,
+            framing: Framing::new(),
+            framer: Framer::new(),
+            sequence: 0,
+            timeout: RESPONSE_TIMEOUT,
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.framing.version()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Send a request answered with `ACK`/`NACK`. Requests with other responses have their own
+    /// methods: [`Client::sync`], [`Client::handshake`] and [`Client::device_info`].
+    pub fn send(&mut self, request: &Request) -> Result<(), ClientError> {
+        validate(request).map_err(ClientError::InvalidRequest)?;
+        let (message, sequence) = self.send_request(request)?;
+        let response = self.wait_response(&message, sequence)?;
+        match response.get_command() {
+            SerialCommand::Ack => Ok(()),
+            _ => Err(nack(&response)),
+        }
+    }
+
+    pub fn ping(&mut self) -> Result<(), ClientError> {
+        self.send(&Request::Ping)
+    }
+
+    /// The device resets right after acknowledging, the transport has to be opened again
+    pub fn reset(&mut self) -> Result<(), ClientError> {
+        self.send(&Request::DeviceReset)
+    }
+
+    pub fn enable_keyboard_input(&mut self) -> Result<(), ClientError> {
+        self.send(&Request::EnableKeyboardInput)
+    }
+
+    pub fn disable_keyboard_input(&mut self) -> Result<(), ClientError> {
+        self.send(&Request::DisableKeyboardInput)
+    }
+
+    pub fn lock_button_state(
+        &mut self,
+        led_idx: usize,
+        state: ButtonState,
+    ) -> Result<(), ClientError> {
+        self.send(&Request::LockButtonState { led_idx, state })
+    }
+
+    pub fn lock_all_button_states(&mut self, state: ButtonState) -> Result<(), ClientError> {
+        self.send(&Request::LockAllButtonStates { state })
+    }
+
+    pub fn unlock_button_state(&mut self, led_idx: usize) -> Result<(), ClientError> {
+        self.send(&Request::UnlockButtonState { led_idx })
+    }
+
+    pub fn unlock_all_button_states(&mut self) -> Result<(), ClientError> {
+        self.send(&Request::UnlockAllButtonStates)
+    }
+
+    /// Events are then returned by [`Client::next_event`]
+    pub fn enable_button_events(&mut self) -> Result<(), ClientError> {
+        self.send(&Request::EnableButtonEvents)
+    }
+
+    pub fn disable_button_events(&mut self) -> Result<(), ClientError> {
+        self.send(&Request::DisableButtonEvents)
+    }
+
+    /// Gamma of the red, green and blue channels in tenths
+    pub fn set_gamma(&mut self, gamma: [u8; 3]) -> Result<(), ClientError> {
+        self.send(&Request::SetGamma { gamma })
+    }
+
+    /// `None` sets the correction of all leds
+    pub fn set_colour_correction(
+        &mut self,
+        led_idx: Option<usize>,
+        correction: Colour,
+    ) -> Result<(), ClientError> {
+        self.send(&Request::SetColourCorrection {
+            led_idx,
+            correction,
+        })
+    }
+
+    pub fn set_global_brightness(&mut self, brightness: u8) -> Result<(), ClientError> {
+        self.send(&Request::SetGlobalBrightness { brightness })
+    }
+
+    pub fn set_dithering(&mut self, enabled: bool) -> Result<(), ClientError> {
+        self.send(&Request::SetDithering { enabled })
+    }
+
+    /// Flags are checked by the branches of the state queues, see [`Flow`]
+    pub fn set_flag(&mut self, flag: Flag, value: bool) -> Result<(), ClientError> {
+        self.send(&Request::SetFlag { flag, value })
+    }
+
+    pub fn add_state(
+        &mut self,
+        led_idx: usize,
+        state_idx: usize,
+        for_state: ButtonState,
+        transition: Transition,
+    ) -> Result<(), ClientError> {
+        self.send(&Request::AddState {
+            led_idx,
+            state_idx,
+            for_state,
+            transition,
+        })
+    }
+
+    pub fn remove_state(
+        &mut self,
+        led_idx: usize,
+        state_idx: usize,
+        for_state: ButtonState,
+    ) -> Result<(), ClientError> {
+        self.send(&Request::RemoveState {
+            led_idx,
+            state_idx,
+            for_state,
+        })
+    }
+
+    /// The flow is reset whenever the state of the slot is added or removed, so it is set after
+    /// the state
+    pub fn set_state_flow(
+        &mut self,
+        led_idx: usize,
+        state_idx: usize,
+        for_state: ButtonState,
+        flow: Flow,
+    ) -> Result<(), ClientError> {
+        self.send(&Request::SetStateFlow {
+            led_idx,
+            state_idx,
+            for_state,
+            flow,
+        })
+    }
+
+    pub fn clear_states(
+        &mut self,
+        led_idx: usize,
+        for_state: ButtonState,
+    ) -> Result<(), ClientError> {
+        self.send(&Request::ClearStates { led_idx, for_state })
+    }
+
+    pub fn begin_batch(&mut self) -> Result<(), ClientError> {
+        self.send(&Request::BeginBatch)
+    }
+
+    pub fn commit_batch(&mut self) -> Result<(), ClientError> {
+        self.send(&Request::CommitBatch)
+    }
+
+    pub fn abort_batch(&mut self) -> Result<(), ClientError> {
+        self.send(&Request::AbortBatch)
+    }
+
+    /// Switch to another protocol version, returns the version used by the device afterwards
+    pub fn handshake(&mut self, version: ProtocolVersion) -> Result<ProtocolVersion, ClientError> {
+        let (message, sequence) = self.send_request(&Request::ProtocolHandshake {
+            version: Some(version),
+        })?;
+        let response = self.wait_response(&message, sequence)?;
+        if !matches!(response.get_command(), SerialCommand::ProtocolHandshake) {
+            return Err(nack(&response));
+        }
+        let selected = ProtocolVersion::try_from(response.get_data()[0])
+            .map_err(|_| ClientError::InvalidResponse)?;
+        // The response is still sent using the previous version
+        self.framing.set_version(selected);
+        Ok(selected)
+    }
+
+    pub fn device_info(&mut self) -> Result<DeviceInfo, ClientError> {
+        let (message, sequence) = self.send_request(&Request::DeviceInfo)?;
+        let mut assembler = MessageAssembler::new();
+        loop {
+            let response = self.wait_response(&message, sequence)?;
+            if !matches!(response.get_command(), SerialCommand::DeviceInfo) {
+                return Err(nack(&response));
+            }
+            if let Some(payload) = assembler
+                .push(response, None)
+                .map_err(|_| ClientError::InvalidResponse)?
+            {
+                return DeviceInfo::try_from(payload.get_payload());
+            }
+        }
+    }
+
+    pub fn sync(&mut self) -> Result<SyncState, ClientError> {
+        let (message, sequence) = self.send_request(&Request::SyncRequest)?;
+        let mut state = SyncState {
+            keyboard_input_enabled: false,
+            flags: 0,
+            leds: Vec::new(),
+        };
+        // Every record is a message of its own, longer transitions are chains
+        let mut assembler = MessageAssembler::new();
+        loop {
+            let response = self.wait_response(&message, sequence)?;
+            let Some(record) = assembler
+                .push(response, None)
+                .map_err(|_| ClientError::InvalidResponse)?
+            else {
+                continue;
+            };
+            let data = record.header().get_data();
+            match record.get_command() {
+                SerialCommand::SyncRequest if data[0] == SyncRecord::Device as u8 => {
+                    state.keyboard_input_enabled = data[1] != 0;
+                    state.flags = data[4];
+                }
+                SerialCommand::SyncRequest if data[0] == SyncRecord::Led as u8 => {
+                    state.leds.push(SyncedLed {
+                        led_idx: data[1] as usize,
+                        status: LedStatus {
+                            button_state: button_state(data[2])?,
+                            lock_state: match data[3] {
+                                SYNC_UNLOCKED => None,
+                                state => Some(button_state(state)?),
+                            },
+                            idle_element: data[4] as usize,
+                            pressed_element: data[5] as usize,
+                        },
+                        states: Vec::new(),
+                    });
+                }
+                SerialCommand::AddState | SerialCommand::SetStateFlow => {
+                    // States are always sent right after their led
+                    let led = state.leds.last_mut().ok_or(ClientError::InvalidResponse)?;
+                    match Request::try_from(&record).map_err(|_| ClientError::InvalidResponse)? {
+                        Request::AddState {
+                            state_idx,
+                            for_state,
+                            transition,
+                            ..
+                        } => led.states.push(SyncedState {
+                            for_state,
+                            state_idx,
+                            transition,
+                            flow: Flow::default(),
+                        }),
+                        // Flows are sent right after the state of their slot
+                        Request::SetStateFlow { flow, .. } => {
+                            led.states
+                                .last_mut()
+                                .ok_or(ClientError::InvalidResponse)?
+                                .flow = flow
+                        }
+                        _ => return Err(ClientError::InvalidResponse),
+                    }
+                }
+                SerialCommand::Ack => return Ok(state),
+                _ => return Err(nack(record.header())),
+            }
+        }
+    }
+
+    /// Next button event, waiting for at most `timeout`. Events have to be enabled first with
+    /// [`Client::enable_button_events`].
+    pub fn next_event(&mut self, timeout: Duration) -> Result<Option<ButtonEvent>, ClientError> {
+        let deadline = Instant::now() + timeout;
+        while self.events.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.receive(remaining) {
+                // Late response to a request that already timed out
+                Ok(_) => {}
+                Err(ClientError::Timeout) => break,
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(self.events.pop_front())
+    }
+
+    /// Send all frames of a request numbered with consecutive sequence numbers, returns the first
+    /// frame (the one responded to) and the number of the last frame (the one sent in responses)
+    fn send_request(&mut self, request: &Request) -> Result<(SerialMessage, u8), ClientError> {
+        let messages = request.to_messages();
+        for message in &messages {
+            self.sequence = self.sequence.wrapping_add(1);
+            self.framing.set_sequence(self.sequence);
+            self.transport.write_all(&self.framing.encode(message))?;
+        }
+        self.transport.flush()?;
+        Ok((messages[0].clone(), self.sequence))
+    }
+
+    /// Wait for the next frame responding to `request`, skipping late responses to previous ones.
+    /// The skipped frames do not extend the timeout.
+    fn wait_response(
+        &mut self,
+        request: &SerialMessage,
+        sequence: u8,
+    ) -> Result<SerialMessage, ClientError> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let response = self.receive(remaining)?;
+            if self.is_response(request, sequence, &response) {
+                return Ok(response);
+            }
+        }
+    }
+
+    fn is_response(&self, request: &SerialMessage, sequence: u8, response: &SerialMessage) -> bool {
+        match self.framing.version() {
+            ProtocolVersion::V2 => self.framing.sequence() == sequence,
+            ProtocolVersion::V1 => {
+                let request = request.to_bytes();
+                let data = response.get_data();
+                match response.get_command() {
+                    SerialCommand::Ack
+                    | SerialCommand::NackGeneral
+                    | SerialCommand::NackInvalidCommand
+                    | SerialCommand::NackDeviceBusy => data[..] == request[..8],
+                    SerialCommand::NackDeviceError => data[1..] == request[..7],
+                    // Framing errors are reported without the rejected message and apart from
+                    // button events, the device only sends responses
+                    _ => true,
+                }
+            }
+        }
+    }
+
+    /// Next decoded frame other than a button event, events are queued for `next_event`
+    fn receive(&mut self, timeout: Duration) -> Result<SerialMessage, ClientError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            while let Some(frame) = self.framer.next_frame(self.framing.version()) {
+                // Frames which cannot be decoded cannot be matched to a request either
+                let Ok(message) = frame.and_then(|frame| self.framing.decode(&frame)) else {
+                    continue;
+                };
+                match message.get_command() {
+                    SerialCommand::ButtonEvent => self.events.push_back(button_event(&message)?),
+                    _ => return Ok(message),
+                }
+            }
+            // Also when frames keep coming, e.g. button events
+            if Instant::now() >= deadline {
+                return Err(ClientError::Timeout);
+            }
+
+            let mut buffer = [0; 64];
+            let received = match self.transport.read(&mut buffer) {
+                Ok(received) => received,
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                    ) =>
+                {
+                    0
+                }
+                Err(error) => return Err(error.into()),
+            };
+            if received > 0 {
+                // Complete frames are always taken first, so a full read always fits
+                let _ = self.framer.push(&buffer[..received]);
+            } else {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Collects the states of an animation as `AddState` requests
+impl AnimationTarget for Vec<Request> {
+    fn add_led_state(
+        &mut self,
+        led_idx: usize,
+        state_idx: usize,
+        transition: Transition,
+        for_state: &ButtonState,
+    ) {
+        self.push(Request::AddState {
+            led_idx,
+            state_idx,
+            for_state: *for_state,
+            transition,
+        });
+    }
+}
+
+/// Indices are sent as 4 bit values, larger ones would address a different led or state
+fn validate(request: &Request) -> Result<(), DeviceError> {
+    let (led_idx, state_idx, next_state) = match request {
+        Request::LockButtonState { led_idx, .. }
+        | Request::UnlockButtonState { led_idx }
+        | Request::ClearStates { led_idx, .. }
+        | Request::SetColourCorrection {
+            led_idx: Some(led_idx),
+            ..
+        } => (*led_idx, 0, 0),
+        Request::RemoveState {
+            led_idx, state_idx, ..
+        } => (*led_idx, *state_idx, 0),
+        Request::AddState {
+            led_idx,
+            state_idx,
+            transition,
+            ..
+        } => (*led_idx, *state_idx, transition.next_state()),
+        Request::SetStateFlow {
+            led_idx,
+            state_idx,
+            flow,
+            ..
+        } => (
+            *led_idx,
+            *state_idx,
+            flow.branch.map_or(0, |branch| branch.target),
+        ),
+        _ => return Ok(()),
+    };
+    if led_idx >= LED_COUNT {
+        Err(DeviceError::InvalidLedIndex)
+    } else if state_idx >= LED_STATE_QUEUE_SIZE || next_state >= LED_STATE_QUEUE_SIZE {
+        Err(DeviceError::InvalidStateIndex)
+    } else {
+        Ok(())
+    }
+}
+
+fn nack(response: &SerialMessage) -> ClientError {
+    let reason = response.get_data()[0];
+    ClientError::Nack(match response.get_command() {
+        SerialCommand::NackGeneral => Nack::General,
+        SerialCommand::NackInvalidCommand => Nack::InvalidCommand,
+        SerialCommand::NackParseError => Nack::ParseError(ParseError::try_from(reason).ok()),
+        SerialCommand::NackDeviceError => Nack::DeviceError(DeviceError::try_from(reason).ok()),
+        SerialCommand::NackDeviceBusy => Nack::DeviceBusy,
+        _ => return ClientError::InvalidResponse,
+    })
+}
+
+fn button_state(value: u8) -> Result<ButtonState, ClientError> {
+    ButtonState::try_from(value).map_err(|_| ClientError::InvalidResponse)
+}
+
+fn button_event(message: &SerialMessage) -> Result<ButtonEvent, ClientError> {
+    let data = message.get_data();
+    Ok(ButtonEvent {
+        led_idx: data[0] & 0b00001111,
+        state: button_state(data[0] >> 7)?,
+        timestamp_ms: u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as u64,
+    })
+}
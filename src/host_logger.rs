@@ -0,0 +1,14 @@
+//! `defmt` logger of the host programs.
+//!
+//! Firmware logs are encoded for a debug probe, so they are dropped on the host. The firmware
+//! installs its own logger, which is why this one is behind the `host-logger` feature.
+
+#[defmt::global_logger]
+struct DiscardLogger;
+
+unsafe impl defmt::Logger for DiscardLogger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
@@ -1,15 +1,34 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 use defmt::Format;
 use rand::RngCore;
 
 pub mod animations;
 pub mod board;
+pub mod calibration;
+pub mod flow;
+#[cfg(feature = "std")]
+pub mod host;
+#[cfg(feature = "host-logger")]
+mod host_logger;
+#[cfg(feature = "std")]
+pub mod mock;
+pub mod request;
 pub mod rgbleds;
+pub mod serial_connection;
 pub mod serial_protocol;
+pub mod text_protocol;
 pub mod transitions;
+#[cfg(feature = "rp2040")]
 pub mod usb_device;
 
+pub const BUTTON_COUNT: usize = 16;
+pub const LED_COUNT: usize = 16;
+
+/// Hues go around the colour wheel in `0..HUE_RANGE`, 256 steps between each primary and
+/// secondary colour
+pub const HUE_RANGE: u16 = 6 * 256;
+
 #[derive(Clone)]
 pub struct Button {
     _code: ButtonCode,
@@ -96,7 +115,7 @@ impl TryFrom<u32> for ButtonCode {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Colour {
     red: u8,
     green: u8,
@@ -124,9 +143,103 @@ impl Colour {
         }
     }
 
+    /// Red, green and blue, in the order sent in messages
+    pub fn to_bytes(&self) -> [u8; 3] {
+        [self.red, self.green, self.blue]
+    }
+
     pub fn invert(&self) -> Colour {
         Colour::rgb(!self.red, !self.green, !self.blue)
     }
+
+    /// Colour from a hue (see [`HUE_RANGE`]), saturation and value
+    pub fn hsv(hue: u16, saturation: u8, value: u8) -> Colour {
+        let (saturation, value) = (saturation as u32, value as u32);
+        let min = value - (value * saturation + 127) / 255;
+        Colour::from_hue(hue, min, value)
+    }
+
+    /// Hue, saturation and value, the inverse of [`Colour::hsv`] up to rounding. The hue of a
+    /// grey is 0.
+    pub fn to_hsv(&self) -> (u16, u8, u8) {
+        let (min, max) = self.min_max();
+        let saturation = match max {
+            0 => 0,
+            max => ((max - min) * 255 + max / 2) / max,
+        };
+        (self.hue(), saturation as u8, max as u8)
+    }
+
+    /// Colour from a hue (see [`HUE_RANGE`]), saturation and lightness
+    pub fn hsl(hue: u16, saturation: u8, lightness: u8) -> Colour {
+        let (saturation, lightness) = (saturation as u32, lightness as u32);
+        let chroma = ((255 - lightness.abs_diff(255 - lightness)) * saturation + 127) / 255;
+        let min = lightness - chroma / 2;
+        Colour::from_hue(hue, min, min + chroma)
+    }
+
+    /// Hue, saturation and lightness, the inverse of [`Colour::hsl`] up to rounding. The hue
+    /// of a grey is 0.
+    pub fn to_hsl(&self) -> (u16, u8, u8) {
+        let (min, max) = self.min_max();
+        let saturation = match 255 - (max + min).abs_diff(255) {
+            0 => 0,
+            range => ((max - min) * 255 + range / 2) / range,
+        };
+        (
+            self.hue(),
+            saturation.min(255) as u8,
+            (max + min).div_ceil(2) as u8,
+        )
+    }
+
+    fn min_max(&self) -> (u32, u32) {
+        let (red, green, blue) = (self.red as u32, self.green as u32, self.blue as u32);
+        (red.min(green).min(blue), red.max(green).max(blue))
+    }
+
+    /// Colour with the given hue, the smallest component `min` and the largest `max`
+    fn from_hue(hue: u16, min: u32, max: u32) -> Colour {
+        let hue = hue % HUE_RANGE;
+        let (sector, offset) = (hue / 256, hue as u32 % 256);
+        let step = ((max - min) * offset + 128) / 256;
+        let (min, max, rising, falling) =
+            (min as u8, max as u8, (min + step) as u8, (max - step) as u8);
+        match sector {
+            0 => Colour::rgb(max, rising, min),
+            1 => Colour::rgb(falling, max, min),
+            2 => Colour::rgb(min, max, rising),
+            3 => Colour::rgb(min, falling, max),
+            4 => Colour::rgb(rising, min, max),
+            _ => Colour::rgb(max, min, falling),
+        }
+    }
+
+    /// Position on the colour wheel, see [`HUE_RANGE`]
+    fn hue(&self) -> u16 {
+        let (red, green, blue) = (self.red as u32, self.green as u32, self.blue as u32);
+        let (min, max) = self.min_max();
+        let delta = max - min;
+        if delta == 0 {
+            return 0;
+        }
+        // Position of the middle component between the smallest and the largest one
+        let offset = |from: u32, to: u32| ((to - from) * 256 + delta / 2) / delta;
+        let hue = if max == red && blue == min {
+            offset(min, green)
+        } else if max == green && blue == min {
+            256 + offset(red, max)
+        } else if max == green {
+            2 * 256 + offset(min, blue)
+        } else if max == blue && red == min {
+            3 * 256 + offset(green, max)
+        } else if max == blue {
+            4 * 256 + offset(min, red)
+        } else {
+            5 * 256 + offset(blue, max)
+        };
+        (hue % HUE_RANGE as u32) as u16
+    }
 }
 
 #[derive(Format, PartialEq, Clone, Copy, Debug)]
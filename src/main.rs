@@ -21,6 +21,7 @@ use embassy_time::{Duration, Ticker};
 use embedded_alloc::Heap;
 use pico_soundboard::animations::loading_circle;
 use pico_soundboard::board::Board;
+use pico_soundboard::rgbleds::LedOutput;
 use pico_soundboard::usb_device::setup_usb_device;
 use pico_soundboard::{ButtonState, Colour};
 use {defmt_rtt as _, panic_probe as _};
@@ -64,15 +65,16 @@ async fn main(_spawner: Spawner) {
     config.polarity = Polarity::IdleLow;
 
     let spi = Spi::new(p.SPI0, clk, mosi, miso, p.DMA_CH0, p.DMA_CH1, config);
+    let mut leds = LedOutput::new(spi).await;
 
     // RefCell needed for mutable access
-    let board: Mutex<ThreadModeRawMutex, _> = Mutex::new(RefCell::new(Board::new(i2c, spi).await));
+    let board: Mutex<ThreadModeRawMutex, _> = Mutex::new(RefCell::new(Board::new(i2c)));
     info!("Board initialised!");
 
     {
         let mut _board = board.lock().await;
         _board.get_mut().lock_led_states(&ButtonState::Idle);
-        loading_circle(&mut _board.get_mut(), Colour::rgb(0x50, 0x0, 0x50), 100);
+        loading_circle(_board.get_mut(), Colour::rgb(0x50, 0x0, 0x50), 100);
         // Await serial connection to proceed
     }
 
@@ -80,9 +82,7 @@ async fn main(_spawner: Spawner) {
     let rgb_fut = async {
         let mut ticker = Ticker::every(Duration::from_millis(1));
         loop {
-            {
-                board.lock().await.get_mut().refresh_leds().await;
-            }
+            leds.refresh(&board).await;
             ticker.next().await;
         }
     };
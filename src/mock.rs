@@ -0,0 +1,203 @@
+//! Stand-ins for the hardware of the board, to run `Board` on the host - in the emulator or in
+//! tests.
+
+use core::convert::Infallible;
+use core::future::Future;
+use core::pin::pin;
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::task::{Context, Poll, Waker};
+use std::sync::{Arc, Mutex};
+use std::task::Wake;
+use std::thread::{self, Thread};
+
+use embedded_hal_1::i2c::{ErrorType as I2cErrorType, Operation};
+use embedded_hal_1::spi::ErrorType as SpiErrorType;
+use embedded_hal_async::{i2c::I2c, spi::SpiBus};
+use heapless::Vec;
+
+use crate::rgbleds::{FRAME_LEN, START_FRAME_LEN};
+use crate::LED_COUNT;
+
+/// Port expander with the buttons. Clones share the buttons, so one can be passed to the board
+/// and the other kept to press them.
+#[derive(Clone, Default)]
+pub struct MockI2c {
+    // Bit `n` is button `n` of the expander, not led `n`
+    pressed: Arc<AtomicU16>,
+}
+
+impl MockI2c {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Press the button under the given led
+    pub fn press(&self, led_idx: usize) {
+        self.pressed
+            .fetch_or(button_bit(led_idx), Ordering::Relaxed);
+    }
+
+    pub fn release(&self, led_idx: usize) {
+        self.pressed
+            .fetch_and(!button_bit(led_idx), Ordering::Relaxed);
+    }
+}
+
+fn button_bit(led_idx: usize) -> u16 {
+    1 << ((led_idx + 8) % 16)
+}
+
+impl I2cErrorType for MockI2c {
+    type Error = Infallible;
+}
+
+impl I2c for MockI2c {
+    async fn transaction(
+        &mut self,
+        _address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Infallible> {
+        // The expander pulls the inputs of pressed buttons low
+        let inputs = (!self.pressed.load(Ordering::Relaxed)).to_le_bytes();
+        for operation in operations {
+            if let Operation::Read(buffer) = operation {
+                buffer
+                    .iter_mut()
+                    .zip(inputs.iter().cycle())
+                    .for_each(|(byte, input)| *byte = *input);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Word last sent to a single APA102 led, as recorded by [`MockSpi`]
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct RecordedLed {
+    pub brightness: u8,
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl RecordedLed {
+    /// Colour as seen on the led, the lower 5 bits of the brightness are the global brightness
+    pub fn scaled(&self) -> (u8, u8, u8) {
+        let scale = |value: u8| (value as u16 * (self.brightness & 0x1f) as u16 / 0x1f) as u8;
+        (scale(self.red), scale(self.green), scale(self.blue))
+    }
+}
+
+/// Led chain. Every refresh is a [`LedFrame`](crate::rgbleds::LedFrame), the last complete
+/// refresh can be read from `leds`.
+#[derive(Clone)]
+pub struct MockSpi {
+    word: Vec<u8, 4>,
+    byte_idx: usize,
+    frame: [RecordedLed; LED_COUNT],
+    leds: MockLeds,
+}
+
+impl MockSpi {
+    pub fn new() -> Self {
+        MockSpi {
+            word: Vec::new(),
+            byte_idx: 0,
+            frame: [RecordedLed::default(); LED_COUNT],
+            leds: MockLeds::default(),
+        }
+    }
+
+    pub fn leds(&self) -> MockLeds {
+        self.leds.clone()
+    }
+
+    fn push(&mut self, byte: u8) {
+        if (START_FRAME_LEN..START_FRAME_LEN + 4 * LED_COUNT).contains(&self.byte_idx) {
+            let _ = self.word.push(byte);
+            if self.word.is_full() {
+                self.frame[(self.byte_idx - START_FRAME_LEN) / 4] = RecordedLed {
+                    brightness: self.word[0],
+                    blue: self.word[1],
+                    green: self.word[2],
+                    red: self.word[3],
+                };
+                self.word.clear();
+            }
+        }
+        self.byte_idx += 1;
+        if self.byte_idx == FRAME_LEN {
+            *self.leds.0.lock().unwrap() = self.frame;
+            self.byte_idx = 0;
+        }
+    }
+}
+
+impl Default for MockSpi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpiErrorType for MockSpi {
+    type Error = Infallible;
+}
+
+impl SpiBus for MockSpi {
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+        words.fill(0);
+        Ok(())
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+        words.iter().for_each(|&byte| self.push(byte));
+        Ok(())
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Infallible> {
+        read.fill(0);
+        self.write(write).await
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+        words.iter().for_each(|&byte| self.push(byte));
+        words.fill(0);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/// Output of a `MockSpi`, shared with all of its clones
+#[derive(Clone, Default)]
+pub struct MockLeds(Arc<Mutex<[RecordedLed; LED_COUNT]>>);
+
+impl MockLeds {
+    /// Leds as of the last complete refresh
+    pub fn frame(&self) -> [RecordedLed; LED_COUNT] {
+        *self.0.lock().unwrap()
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Minimal executor, the firmware only needs a single thread polling one future
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        thread::park();
+    }
+}
@@ -0,0 +1,495 @@
+use defmt::{info, Format};
+use embedded_hal_async::i2c::I2c;
+use heapless::Vec;
+
+use crate::{
+    board::Board,
+    calibration::MAX_GAMMA,
+    flow::{Flag, Flow},
+    serial_protocol::{
+        split_payload, AssembledMessage, DeviceError, ParseError, ProtocolVersion, SerialCommand,
+        SerialMessage,
+    },
+    transitions::{Transition, MAX_TRANSITION_PAYLOAD},
+    ButtonState, Colour, LED_COUNT,
+};
+
+/// Command received from the host with all of its fields decoded and validated
+pub enum Request {
+    SyncRequest,
+    /// `None` only queries the supported versions
+    ProtocolHandshake {
+        version: Option<ProtocolVersion>,
+    },
+    DeviceInfo,
+    DeviceReset,
+    DisableKeyboardInput,
+    EnableKeyboardInput,
+    LockButtonState {
+        led_idx: usize,
+        state: ButtonState,
+    },
+    LockAllButtonStates {
+        state: ButtonState,
+    },
+    UnlockButtonState {
+        led_idx: usize,
+    },
+    UnlockAllButtonStates,
+    EnableButtonEvents,
+    DisableButtonEvents,
+    /// Gamma of the red, green and blue channels in tenths
+    SetGamma {
+        gamma: [u8; 3],
+    },
+    /// `None` applies the correction to all leds
+    SetColourCorrection {
+        led_idx: Option<usize>,
+        correction: Colour,
+    },
+    SetGlobalBrightness {
+        brightness: u8,
+    },
+    SetFlag {
+        flag: Flag,
+        value: bool,
+    },
+    SetDithering {
+        enabled: bool,
+    },
+    AddState {
+        led_idx: usize,
+        state_idx: usize,
+        for_state: ButtonState,
+        transition: Transition,
+    },
+    RemoveState {
+        led_idx: usize,
+        state_idx: usize,
+        for_state: ButtonState,
+    },
+    ClearStates {
+        led_idx: usize,
+        for_state: ButtonState,
+    },
+    SetStateFlow {
+        led_idx: usize,
+        state_idx: usize,
+        for_state: ButtonState,
+        flow: Flow,
+    },
+    BeginBatch,
+    CommitBatch,
+    AbortBatch,
+    Ping,
+    /// `ACK`/`NACK` sent by the host - the device never expects them, so they are ignored
+    Response(SerialCommand),
+}
+
+impl Request {
+    /// Requests changing the led state queues, these are staged while a batch is open
+    pub fn is_state_change(&self) -> bool {
+        matches!(
+            self,
+            Request::AddState { .. }
+                | Request::RemoveState { .. }
+                | Request::ClearStates { .. }
+                | Request::SetStateFlow { .. }
+        )
+    }
+
+    /// Encode the request as a chain of messages, the inverse of decoding it from an
+    /// [`AssembledMessage`]. Only `AddState` with a long transition takes more than one message.
+    pub fn to_messages(&self) -> Vec<SerialMessage, MAX_REQUEST_FRAMES> {
+        let (command, data) = match self {
+            Request::SyncRequest => (SerialCommand::SyncRequest, [0; 8]),
+            Request::ProtocolHandshake { version } => (
+                SerialCommand::ProtocolHandshake,
+                data_with(&[version.map_or(0, |version| version as u8)]),
+            ),
+            Request::DeviceInfo => (SerialCommand::DeviceInfo, [0; 8]),
+            Request::DeviceReset => (SerialCommand::DeviceReset, [0; 8]),
+            Request::DisableKeyboardInput => (SerialCommand::DisableKeyboardInput, [0; 8]),
+            Request::EnableKeyboardInput => (SerialCommand::EnableKeyboardInput, [0; 8]),
+            Request::LockButtonState { led_idx, state } => (
+                SerialCommand::LockButtonState,
+                data_with(&[led_and_state(*led_idx, state)]),
+            ),
+            Request::LockAllButtonStates { state } => (
+                SerialCommand::LockAllButtonStates,
+                data_with(&[(*state as u8) << 7]),
+            ),
+            Request::UnlockButtonState { led_idx } => (
+                SerialCommand::UnlockButtonState,
+                data_with(&[*led_idx as u8]),
+            ),
+            Request::UnlockAllButtonStates => (SerialCommand::UnlockAllButtonStates, [0; 8]),
+            Request::EnableButtonEvents => (SerialCommand::EnableButtonEvents, [0; 8]),
+            Request::DisableButtonEvents => (SerialCommand::DisableButtonEvents, [0; 8]),
+            Request::SetGamma { gamma } => (SerialCommand::SetGamma, data_with(gamma)),
+            Request::SetColourCorrection {
+                led_idx,
+                correction,
+            } => (
+                SerialCommand::SetColourCorrection,
+                data_with(&[
+                    led_idx.map_or(ALL_LEDS, |led_idx| led_idx as u8),
+                    correction.red,
+                    correction.green,
+                    correction.blue,
+                ]),
+            ),
+            Request::SetGlobalBrightness { brightness } => (
+                SerialCommand::SetGlobalBrightness,
+                data_with(&[*brightness]),
+            ),
+            Request::SetFlag { flag, value } => (
+                SerialCommand::SetFlag,
+                data_with(&[flag.index(), *value as u8]),
+            ),
+            Request::SetDithering { enabled } => {
+                (SerialCommand::SetDithering, data_with(&[*enabled as u8]))
+            }
+            Request::AddState {
+                led_idx,
+                state_idx,
+                for_state,
+                transition,
+            } => {
+                let payload = transition.to_payload(*led_idx, for_state, *state_idx);
+                return split_payload(SerialCommand::AddState, &payload).collect();
+            }
+            Request::RemoveState {
+                led_idx,
+                state_idx,
+                for_state,
+            } => (
+                SerialCommand::RemoveState,
+                data_with(&[led_and_state(*led_idx, for_state), (*state_idx as u8) << 4]),
+            ),
+            Request::ClearStates { led_idx, for_state } => (
+                SerialCommand::ClearStates,
+                data_with(&[led_and_state(*led_idx, for_state)]),
+            ),
+            Request::SetStateFlow {
+                led_idx,
+                state_idx,
+                for_state,
+                flow,
+            } => (
+                SerialCommand::SetStateFlow,
+                flow.to_data(*led_idx, for_state, *state_idx),
+            ),
+            Request::BeginBatch => (SerialCommand::BeginBatch, [0; 8]),
+            Request::CommitBatch => (SerialCommand::CommitBatch, [0; 8]),
+            Request::AbortBatch => (SerialCommand::AbortBatch, [0; 8]),
+            Request::Ping => (SerialCommand::Ping, [0; 8]),
+            Request::Response(command) => (*command, [0; 8]),
+        };
+        Vec::from_iter([SerialMessage::new(
+            command,
+            data,
+            SerialCommand::EndOfStream,
+        )])
+    }
+
+    /// Apply a request that only changes the state of the board.
+    ///
+    /// Requests that need to communicate with the host or change the state of the connection
+    /// (e.g. `SyncRequest`, `ProtocolHandshake`) are left to the caller and ignored here.
+    pub fn apply<I2C: I2c>(self, board: &mut Board<I2C>) {
+        match self {
+            Request::DisableKeyboardInput => board.disable_keyboard_input(),
+            Request::EnableKeyboardInput => board.enable_keyboard_input(),
+            Request::LockButtonState { led_idx, state } => board.lock_led_state(led_idx, &state),
+            Request::LockAllButtonStates { state } => board.lock_led_states(&state),
+            Request::UnlockButtonState { led_idx } => board.unlock_led_state(led_idx),
+            Request::UnlockAllButtonStates => board.unlock_led_states(),
+            Request::SetGamma { gamma } => board.set_gamma(gamma),
+            Request::SetColourCorrection {
+                led_idx,
+                correction,
+            } => match led_idx {
+                Some(led_idx) => board.set_colour_correction(led_idx, correction),
+                None => board.set_colour_corrections(correction),
+            },
+            Request::SetGlobalBrightness { brightness } => board.set_global_brightness(brightness),
+            Request::SetFlag { flag, value } => board.set_flag(flag, value),
+            Request::SetDithering { enabled } => board.set_dithering(enabled),
+            Request::AddState {
+                led_idx,
+                state_idx,
+                for_state,
+                transition,
+            } => {
+                info!(
+                    "Added state for led {}: for_state: {}, state index: {}",
+                    led_idx, for_state, state_idx
+                );
+                board.add_led_state(led_idx, state_idx, transition, &for_state);
+            }
+            Request::RemoveState {
+                led_idx,
+                state_idx,
+                for_state,
+            } => board.remove_led_state(led_idx, state_idx, &for_state),
+            Request::ClearStates { led_idx, for_state } => {
+                board.clear_led_queue(led_idx, &[&for_state])
+            }
+            Request::SetStateFlow {
+                led_idx,
+                state_idx,
+                for_state,
+                flow,
+            } => board.set_led_state_flow(led_idx, state_idx, flow, &for_state),
+            Request::SyncRequest
+            | Request::ProtocolHandshake { .. }
+            | Request::DeviceInfo
+            | Request::DeviceReset
+            | Request::EnableButtonEvents
+            | Request::DisableButtonEvents
+            | Request::BeginBatch
+            | Request::CommitBatch
+            | Request::AbortBatch
+            | Request::Ping
+            | Request::Response(_) => {}
+        }
+    }
+}
+
+/// Led index of `SetColourCorrection` addressing all leds at once
+pub const ALL_LEDS: u8 = 0xff;
+
+/// Longest chain of messages of a request, see [`Request::to_messages`]
+pub const MAX_REQUEST_FRAMES: usize = MAX_TRANSITION_PAYLOAD.div_ceil(8);
+
+pub const MAX_BATCH_SIZE: usize = 64;
+
+/// State changes staged between `BeginBatch` and `CommitBatch`, so that they are all visible
+/// starting from the same led refresh
+pub struct Batch {
+    requests: Vec<Request, MAX_BATCH_SIZE>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Batch {
+            requests: Vec::new(),
+        }
+    }
+
+    pub fn stage(&mut self, request: Request) -> Result<(), DeviceError> {
+        self.requests
+            .push(request)
+            .map_err(|_| DeviceError::BatchOverflow)
+    }
+
+    /// Apply all staged requests in the order they were received
+    pub fn commit<I2C: I2c>(self, board: &mut Board<I2C>) {
+        info!("Committing a batch of {} requests", self.requests.len());
+        self.requests
+            .into_iter()
+            .for_each(|request| request.apply(board));
+    }
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reason for rejecting a message, determines the type of the `NACK`
+#[derive(Format, Debug)]
+pub enum RequestError {
+    InvalidCommand,
+    Parse(ParseError),
+    Device(DeviceError),
+}
+
+impl From<ParseError> for RequestError {
+    fn from(value: ParseError) -> Self {
+        RequestError::Parse(value)
+    }
+}
+
+impl From<DeviceError> for RequestError {
+    fn from(value: DeviceError) -> Self {
+        RequestError::Device(value)
+    }
+}
+
+impl TryFrom<&AssembledMessage> for Request {
+    type Error = RequestError;
+
+    fn try_from(message: &AssembledMessage) -> Result<Self, Self::Error> {
+        let data = message.header().get_data();
+        match message.get_command() {
+            SerialCommand::EndOfStream
+            | SerialCommand::ToBeContinued
+            | SerialCommand::ButtonEvent
+            | SerialCommand::Reserved => Err(RequestError::InvalidCommand),
+            SerialCommand::SyncRequest => Ok(Request::SyncRequest),
+            SerialCommand::ProtocolHandshake => Ok(Request::ProtocolHandshake {
+                version: ProtocolVersion::try_from(data[0]).ok(),
+            }),
+            SerialCommand::DeviceInfo => Ok(Request::DeviceInfo),
+            SerialCommand::DeviceReset => Ok(Request::DeviceReset),
+            SerialCommand::DisableKeyboardInput => Ok(Request::DisableKeyboardInput),
+            SerialCommand::EnableKeyboardInput => Ok(Request::EnableKeyboardInput),
+            SerialCommand::LockButtonState => {
+                let (led_idx, state) = parse_led_and_state(data[0])?;
+                Ok(Request::LockButtonState { led_idx, state })
+            }
+            SerialCommand::LockAllButtonStates => Ok(Request::LockAllButtonStates {
+                state: parse_button_state(data[0])?,
+            }),
+            SerialCommand::UnlockButtonState => Ok(Request::UnlockButtonState {
+                led_idx: parse_led_idx(data[0])?,
+            }),
+            SerialCommand::UnlockAllButtonStates => Ok(Request::UnlockAllButtonStates),
+            SerialCommand::EnableButtonEvents => Ok(Request::EnableButtonEvents),
+            SerialCommand::DisableButtonEvents => Ok(Request::DisableButtonEvents),
+            SerialCommand::SetGamma => Ok(Request::SetGamma {
+                gamma: [
+                    parse_gamma(data[0])?,
+                    parse_gamma(data[1])?,
+                    parse_gamma(data[2])?,
+                ],
+            }),
+            SerialCommand::SetColourCorrection => Ok(Request::SetColourCorrection {
+                led_idx: match data[0] {
+                    ALL_LEDS => None,
+                    byte => Some(parse_led_idx(byte)?),
+                },
+                correction: Colour::rgb(data[1], data[2], data[3]),
+            }),
+            SerialCommand::SetGlobalBrightness => Ok(Request::SetGlobalBrightness {
+                brightness: data[0],
+            }),
+            SerialCommand::SetFlag => Ok(Request::SetFlag {
+                flag: parse_flag(data[0])?,
+                value: parse_bool(data[1])?,
+            }),
+            SerialCommand::SetDithering => Ok(Request::SetDithering {
+                enabled: parse_bool(data[0])?,
+            }),
+            SerialCommand::AddState => {
+                // Bits 6-4 are the kind of the transition, checked when parsing it
+                let (led_idx, for_state) = led_and_state_bits(data[0]);
+                Ok(Request::AddState {
+                    led_idx,
+                    state_idx: (data[1] >> 4) as usize,
+                    for_state,
+                    transition: Transition::try_from(message.get_payload())?,
+                })
+            }
+            SerialCommand::RemoveState => {
+                let (led_idx, for_state) = parse_led_and_state(data[0])?;
+                if data[1] & 0b00001111 != 0 {
+                    return Err(ParseError::InvalidData.into());
+                }
+                Ok(Request::RemoveState {
+                    led_idx,
+                    state_idx: (data[1] >> 4) as usize,
+                    for_state,
+                })
+            }
+            SerialCommand::ClearStates => {
+                let (led_idx, for_state) = parse_led_and_state(data[0])?;
+                Ok(Request::ClearStates { led_idx, for_state })
+            }
+            SerialCommand::SetStateFlow => {
+                let (led_idx, for_state) = parse_led_and_state(data[0])?;
+                Ok(Request::SetStateFlow {
+                    led_idx,
+                    // The low nibble is the target of the branch
+                    state_idx: (data[1] >> 4) as usize,
+                    for_state,
+                    flow: Flow::from_data(data)?,
+                })
+            }
+            SerialCommand::BeginBatch => Ok(Request::BeginBatch),
+            SerialCommand::CommitBatch => Ok(Request::CommitBatch),
+            SerialCommand::AbortBatch => Ok(Request::AbortBatch),
+            SerialCommand::Ping => Ok(Request::Ping),
+            command @ (SerialCommand::NackGeneral
+            | SerialCommand::NackInvalidCommand
+            | SerialCommand::NackParseError
+            | SerialCommand::NackDeviceError
+            | SerialCommand::NackDeviceBusy
+            | SerialCommand::Ack) => Ok(Request::Response(*command)),
+        }
+    }
+}
+
+/// Led index taking the whole byte
+fn parse_led_idx(byte: u8) -> Result<usize, RequestError> {
+    if (byte as usize) < LED_COUNT {
+        Ok(byte as usize)
+    } else {
+        Err(DeviceError::InvalidLedIndex.into())
+    }
+}
+
+/// Led index in the low nibble and button state in the highest bit, the bits in between are
+/// reserved and have to be `0`
+fn parse_led_and_state(byte: u8) -> Result<(usize, ButtonState), RequestError> {
+    if byte & 0b01110000 != 0 {
+        return Err(ParseError::InvalidData.into());
+    }
+    Ok(led_and_state_bits(byte))
+}
+
+/// Led index and button state of a byte encoded by [`led_and_state`], ignoring bits 6-4. Every
+/// nibble is a valid led index.
+fn led_and_state_bits(byte: u8) -> (usize, ButtonState) {
+    let state = match byte >> 7 {
+        0 => ButtonState::Idle,
+        _ => ButtonState::Pressed,
+    };
+    ((byte & 0b00001111) as usize, state)
+}
+
+/// Gamma in tenths, between 0.1 and [`MAX_GAMMA`]
+fn parse_gamma(byte: u8) -> Result<u8, RequestError> {
+    if (1..=MAX_GAMMA).contains(&byte) {
+        Ok(byte)
+    } else {
+        Err(ParseError::InvalidData.into())
+    }
+}
+
+/// `0x00` or `0x01`
+fn parse_bool(byte: u8) -> Result<bool, RequestError> {
+    match byte {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(ParseError::InvalidData.into()),
+    }
+}
+
+fn parse_flag(byte: u8) -> Result<Flag, RequestError> {
+    Flag::new(byte).ok_or(ParseError::InvalidData.into())
+}
+
+/// Button state in the highest bit, the other bits are reserved and have to be `0`
+fn parse_button_state(byte: u8) -> Result<ButtonState, RequestError> {
+    match byte {
+        0b00000000 => Ok(ButtonState::Idle),
+        0b10000000 => Ok(ButtonState::Pressed),
+        _ => Err(ParseError::InvalidData.into()),
+    }
+}
+
+/// Button state in the highest bit, led index in the low nibble
+fn led_and_state(led_idx: usize, state: &ButtonState) -> u8 {
+    (*state as u8) << 7 | (led_idx as u8 & 0b00001111)
+}
+
+/// Data bytes starting with `bytes`, padded with zeros
+pub(crate) fn data_with(bytes: &[u8]) -> [u8; 8] {
+    let mut data = [0; 8];
+    data[..bytes.len()].copy_from_slice(bytes);
+    data
+}
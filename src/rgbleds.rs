@@ -1,31 +1,109 @@
-extern crate alloc;
-use alloc::boxed::Box;
-
-use embedded_hal_async::spi::SpiBus;
+use embassy_futures::join::join;
+use embassy_time::{Duration, Instant};
+use embedded_hal_async::{i2c::I2c, spi::SpiBus};
 use heapless::Vec;
 
 use crate::{
-    transitions::{TransitionFunction, TransitionResult},
-    ButtonState, Colour,
+    calibration::Calibration,
+    flow::{Flag, Flow, FlowInputs},
+    serial_connection::SharedBoard,
+    transitions::{Transition, TransitionResult},
+    ButtonState, Colour, LED_COUNT,
+};
+
+pub(crate) const START_FRAME_LEN: usize = 4;
+// The data is delayed by half a clock at every led, so the last led needs another half clock per
+// led to receive its word. It is never shorter than the 4 bytes sent before, so that the bytes on
+// the wire stay the same.
+const END_FRAME_LEN: usize = if LED_COUNT.div_ceil(16) > 4 {
+    LED_COUNT.div_ceil(16)
+} else {
+    4
 };
+pub const FRAME_LEN: usize = START_FRAME_LEN + 4 * LED_COUNT + END_FRAME_LEN;
+
+/// Everything sent to the leds in one refresh: the start frame, one word per led and the end
+/// frame, sent in a single transfer
+#[derive(Clone, Copy)]
+pub struct LedFrame([u8; FRAME_LEN]);
+
+impl LedFrame {
+    /// All leds off
+    pub fn new() -> Self {
+        let mut frame = Self([0; FRAME_LEN]);
+        (0..LED_COUNT).for_each(|i| frame.set(i, [0b11100000, 0, 0, 0]));
+        frame
+    }
+
+    pub fn set(&mut self, index: usize, word: [u8; 4]) {
+        let start = START_FRAME_LEN + 4 * (index % LED_COUNT);
+        self.0[start..start + 4].copy_from_slice(&word);
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
 
-pub(crate) struct RGBLeds<SPI> {
+impl Default for LedFrame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Owner of the led bus, kept out of the [`Board`](crate::board::Board) so that sending a frame
+/// does not hold the board.
+///
+/// Frames are double buffered: while the front frame is sent, the next one is rendered into the
+/// back frame, then they are swapped.
+pub struct LedOutput<SPI> {
     spi: SPI,
-    _start_frame: [u8; 4],
+    front: LedFrame,
+    back: LedFrame,
+}
+
+impl<SPI: SpiBus> LedOutput<SPI> {
+    pub async fn new(spi: SPI) -> Self {
+        let mut output = Self {
+            spi,
+            front: LedFrame::new(),
+            back: LedFrame::new(),
+        };
+        // Needed for initialisation
+        let mut white = LedFrame::new();
+        (0..LED_COUNT).for_each(|i| white.set(i, [0xff; 4]));
+        output.spi.write(white.as_bytes()).await.unwrap();
+        output.spi.write(output.front.as_bytes()).await.unwrap();
+        output
+    }
+
+    /// Send the last rendered frame and render the next one from the board at the same time, the
+    /// board is only locked while rendering
+    pub async fn refresh<I2C: I2c>(&mut self, board: &SharedBoard<I2C>) {
+        let back = &mut self.back;
+        join(self.spi.write(self.front.as_bytes()), async {
+            board.lock().await.get_mut().render_leds(back)
+        })
+        .await
+        .0
+        .unwrap();
+        core::mem::swap(&mut self.front, &mut self.back);
+    }
+}
+
+pub(crate) struct RGBLeds {
     leds: Vec<RGBLed, 16>,
-    _end_frame: [u8; 4],
+    calibration: Calibration,
+    // Bit `i` is flag `i`, set by the host for the branches of the state queues
+    flags: u8,
 }
 
-impl<SPI> RGBLeds<SPI>
-where
-    SPI: SpiBus,
-{
-    pub fn new(spi: SPI) -> Self {
+impl RGBLeds {
+    pub fn new() -> Self {
         let mut l = Self {
-            spi,
-            _start_frame: [0; 4],
             leds: Vec::new(),
-            _end_frame: [0; 4],
+            calibration: Calibration::new(),
+            flags: 0,
         };
         for _ in 0..16 {
             l.leds.push(RGBLed::new()).unwrap();
@@ -33,25 +111,6 @@ where
         l
     }
 
-    pub fn full(&mut self, brightness: u8, colour: Colour) {
-        self.leds.iter_mut().for_each(|led| {
-            led.clear(&[&ButtonState::Idle, &ButtonState::Pressed]);
-            led.add_state(
-                0,
-                Box::new(move |_: usize| {
-                    TransitionResult::InProgress(LedState::new(brightness, &colour))
-                }),
-                &ButtonState::Idle,
-            )
-        });
-    }
-
-    pub fn clear_all(&mut self) {
-        self.leds
-            .iter_mut()
-            .for_each(|led| led.clear(&[&ButtonState::Idle, &ButtonState::Pressed]));
-    }
-
     pub fn clear(&mut self, index: usize, states: &[&ButtonState]) {
         self.leds.get_mut(index % 16).unwrap().clear(states)
     }
@@ -60,7 +119,7 @@ where
         &mut self,
         i: usize,
         state_idx: usize,
-        transition: TransitionFunction,
+        transition: Transition,
         for_state: &ButtonState,
     ) {
         self.leds
@@ -71,13 +130,40 @@ where
 
     pub fn remove_state(&mut self, i: usize, state_idx: usize, from_state: &ButtonState) {
         self.leds
-            .get_mut(i)
+            .get_mut(i % 16)
             .unwrap()
             .remove_state(state_idx, from_state);
     }
 
+    pub fn set_state_flow(
+        &mut self,
+        i: usize,
+        state_idx: usize,
+        flow: Flow,
+        for_state: &ButtonState,
+    ) {
+        self.leds
+            .get_mut(i % 16)
+            .unwrap()
+            .set_state_flow(state_idx, flow, for_state);
+    }
+
+    pub fn set_flag(&mut self, flag: Flag, value: bool) {
+        if value {
+            self.flags |= flag.bit();
+        } else {
+            self.flags &= !flag.bit();
+        }
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
     pub fn set_button_state(&mut self, i: usize, new_state: ButtonState) {
         let led = self.leds.get_mut(i).unwrap();
+        // Presses and holds are counted for the branches even if the state is locked
+        led.track_button(new_state);
         // Do not set the state if it is locked
         if led.lock_state.is_none() && new_state != led.button_state {
             led.button_state = new_state;
@@ -85,27 +171,29 @@ where
                 ButtonState::Pressed => led.on_pressed.restart(),
                 ButtonState::Idle => led.on_idle.restart(),
             };
-            led.counter = 0;
+            led.started_at = None;
         }
     }
 
-    pub async fn refresh(&mut self) {
-        self.spi.write(&self._start_frame).await.unwrap();
-
-        for led in self.leds.iter_mut() {
-            led.run();
-            self.spi
-                .write(&[
-                    led.current_state.brightness,
-                    led.current_state.b,
-                    led.current_state.g,
-                    led.current_state.r,
-                ])
-                .await
-                .unwrap()
+    pub fn render(&mut self, frame: &mut LedFrame) {
+        // The same time for all leds, so that their states stay in step
+        let now = Instant::now();
+        for (i, led) in self.leds.iter_mut().enumerate() {
+            led.run(now, self.flags);
+            let intensity = self.calibration.apply(i, &led.current_state);
+            frame.set(
+                i,
+                led.encoder.encode(intensity, self.calibration.dithering()),
+            );
         }
+    }
 
-        self.spi.write(&self._end_frame).await.unwrap();
+    pub fn calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    pub fn calibration_mut(&mut self) -> &mut Calibration {
+        &mut self.calibration
     }
 
     pub fn lock_led_state(&mut self, index: usize, state: &ButtonState) {
@@ -115,31 +203,69 @@ where
     pub fn unlock_led_state(&mut self, index: usize) {
         self.leds.get_mut(index % 16).unwrap().unlock_state();
     }
+
+    pub fn status(&self, index: usize) -> LedStatus {
+        self.leds.get(index % 16).unwrap().status()
+    }
+
+    pub fn states(
+        &self,
+        index: usize,
+        for_state: &ButtonState,
+    ) -> impl Iterator<Item = (usize, &Transition, &Flow)> {
+        self.leds.get(index % 16).unwrap().states(for_state)
+    }
+}
+
+/// Snapshot of the led state machine, excluding the contents of the state queues
+#[derive(Clone, Copy, Debug)]
+pub struct LedStatus {
+    pub button_state: ButtonState,
+    pub lock_state: Option<ButtonState>,
+    pub idle_element: usize,
+    pub pressed_element: usize,
 }
 
 #[derive(Debug)]
 pub(crate) struct RGBLed {
     current_state: LedState,
+    encoder: LedEncoder,
     button_state: ButtonState,
     on_pressed: LedStateQueue,
     on_idle: LedStateQueue,
-    counter: usize,
+    // Start of the current state, `None` starts it at the next refresh
+    started_at: Option<Instant>,
     lock_state: Option<ButtonState>,
+    // When the button was pressed, regardless of the lock
+    pressed_at: Option<Instant>,
+    press_count: usize,
 }
 
 impl RGBLed {
     pub fn new() -> Self {
         Self {
             current_state: LedState::default(),
+            encoder: LedEncoder::default(),
             button_state: ButtonState::Idle,
             on_pressed: LedStateQueue::new(),
             on_idle: LedStateQueue::new(),
-            counter: 0usize,
+            started_at: None,
             lock_state: None,
+            pressed_at: None,
+            press_count: 0,
         }
     }
 
-    pub fn run(&mut self) {
+    /// Render the state of the led at `now`. The transitions are timed by the clock rather than
+    /// by counting refreshes, so a tick is a millisecond however often the leds are refreshed.
+    pub fn run(&mut self, now: Instant, flags: u8) {
+        let inputs = FlowInputs {
+            held_ticks: self
+                .pressed_at
+                .map_or(0, |pressed_at| ticks_between(pressed_at, now)),
+            press_count: self.press_count,
+            flags,
+        };
         let queue = if let Some(locked_state) = &self.lock_state {
             match locked_state {
                 ButtonState::Pressed => &mut self.on_pressed,
@@ -152,25 +278,35 @@ impl RGBLed {
             }
         };
 
-        if let Some(transition) = queue.current() {
-            match transition(self.counter) {
+        // States that finished since the last refresh are skipped right away, each of them
+        // starting exactly when the previous one ended
+        for _ in 0..LED_STATE_QUEUE_SIZE {
+            let started_at = *self.started_at.get_or_insert(now);
+            match queue.run(ticks_between(started_at, now)) {
                 TransitionResult::InProgress(state) => {
                     self.current_state = state;
-                    self.counter += 1;
+                    return;
                 }
                 TransitionResult::Finished(next_state) => {
                     // Transition complete, move to the next state
-                    queue.advance(next_state);
-                    self.counter = 0;
+                    let ended_at =
+                        started_at + Duration::from_millis(queue.duration_ticks() as u64);
+                    if queue.advance(next_state, &inputs) {
+                        self.press_count = 0;
+                    }
+                    self.started_at = Some(ended_at);
                 }
             }
         }
+        // A whole pass over the queue without anything to render, e.g. it is empty or the leds
+        // were not refreshed for a long time - start over from the next refresh
+        self.started_at = None;
     }
 
     pub fn clear(&mut self, from_states: &[&ButtonState]) {
         for &state in from_states {
             if *state == self.button_state {
-                self.counter = 0;
+                self.started_at = None;
             }
             match state {
                 ButtonState::Pressed => self.on_pressed.clear(),
@@ -179,12 +315,7 @@ impl RGBLed {
         }
     }
 
-    pub fn add_state(
-        &mut self,
-        state_idx: usize,
-        transition: TransitionFunction,
-        for_state: &ButtonState,
-    ) {
+    pub fn add_state(&mut self, state_idx: usize, transition: Transition, for_state: &ButtonState) {
         match for_state {
             ButtonState::Pressed => self.on_pressed.insert(state_idx, transition),
             ButtonState::Idle => self.on_idle.insert(state_idx, transition),
@@ -198,6 +329,24 @@ impl RGBLed {
         };
     }
 
+    pub fn set_state_flow(&mut self, state_idx: usize, flow: Flow, for_state: &ButtonState) {
+        match for_state {
+            ButtonState::Pressed => self.on_pressed.set_flow(state_idx, flow),
+            ButtonState::Idle => self.on_idle.set_flow(state_idx, flow),
+        }
+    }
+
+    fn track_button(&mut self, state: ButtonState) {
+        match state {
+            ButtonState::Pressed if self.pressed_at.is_none() => {
+                self.pressed_at = Some(Instant::now());
+                self.press_count = self.press_count.saturating_add(1);
+            }
+            ButtonState::Pressed => {}
+            ButtonState::Idle => self.pressed_at = None,
+        }
+    }
+
     pub fn lock_state(&mut self, state: &ButtonState) {
         self.lock_state = Some(*state)
     }
@@ -205,85 +354,200 @@ impl RGBLed {
     pub fn unlock_state(&mut self) {
         self.lock_state = None
     }
+
+    pub fn status(&self) -> LedStatus {
+        LedStatus {
+            button_state: self.button_state,
+            lock_state: self.lock_state,
+            idle_element: self.on_idle.current_element,
+            pressed_element: self.on_pressed.current_element,
+        }
+    }
+
+    pub fn states(
+        &self,
+        for_state: &ButtonState,
+    ) -> impl Iterator<Item = (usize, &Transition, &Flow)> {
+        match for_state {
+            ButtonState::Pressed => self.on_pressed.states(),
+            ButtonState::Idle => self.on_idle.states(),
+        }
+    }
 }
 
-const LED_STATE_QUEUE_SIZE: usize = 16;
+pub const LED_STATE_QUEUE_SIZE: usize = 16;
 
+// Every slot is as large as the longest transition, the keyframes, so the queues of all leds take
+// 32 KB of the board. Longer transitions should be kept out of the slots instead of growing them.
+#[cfg(target_pointer_width = "32")]
+const _: () = assert!(core::mem::size_of::<Option<Transition>>() <= 64);
+
+/// Empty slots in the queue are skipped, advancing to the next slot
+#[derive(Clone, Debug, PartialEq)]
 struct LedStateQueue {
-    queue: Vec<TransitionFunction, LED_STATE_QUEUE_SIZE>,
+    queue: [Option<Transition>; LED_STATE_QUEUE_SIZE],
+    flows: [Flow; LED_STATE_QUEUE_SIZE],
     current_element: usize,
-}
-
-impl core::fmt::Debug for LedStateQueue {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_struct("LedStateQueue")
-            .field("current_element", &self.current_element)
-            .finish()
-    }
+    // Times the current state has been played, for its repeat count
+    plays: usize,
 }
 
 impl LedStateQueue {
     pub fn new() -> Self {
-        let mut q = Self {
-            queue: Vec::new(),
+        Self {
+            queue: [None; LED_STATE_QUEUE_SIZE],
+            flows: [Flow::default(); LED_STATE_QUEUE_SIZE],
             current_element: 0,
-        };
-        for i in 0..LED_STATE_QUEUE_SIZE {
-            q.insert(
-                i,
-                Box::new(move |_| TransitionResult::Finished((i + 1) % LED_STATE_QUEUE_SIZE)),
-            )
+            plays: 0,
         }
-        q
     }
 
-    pub fn advance(&mut self, to_element: usize) {
-        self.current_element = to_element % LED_STATE_QUEUE_SIZE;
+    /// Move on from the finished state, to `to_element` unless the flow of its slot repeats it
+    /// or branches elsewhere. Returns whether a branch was taken.
+    pub fn advance(&mut self, to_element: usize, inputs: &FlowInputs) -> bool {
+        let flow = self.flows[self.current_element];
+        self.plays += 1;
+        if self.plays < flow.repeat_count as usize {
+            return false;
+        }
+        self.plays = 0;
+        let branch = flow.branch.filter(|branch| branch.condition.holds(inputs));
+        self.current_element =
+            branch.map_or(to_element, |branch| branch.target) % LED_STATE_QUEUE_SIZE;
+        branch.is_some()
     }
 
-    pub fn current(&self) -> Option<&TransitionFunction> {
-        self.queue.get(self.current_element)
+    pub fn run(&self, ticks: usize) -> TransitionResult {
+        match &self.queue[self.current_element] {
+            Some(transition) => transition.render(ticks),
+            None => TransitionResult::Finished(self.current_element + 1),
+        }
     }
 
-    pub fn insert(&mut self, position: usize, f: TransitionFunction) {
-        if self.queue.len() <= position {
-            self.queue.push(f).ok();
-        } else {
-            self.queue[position] = f;
-        }
+    /// Ticks until the current state finishes, see [`Transition::duration_ticks`]
+    pub fn duration_ticks(&self) -> usize {
+        self.queue[self.current_element]
+            .as_ref()
+            .map_or(0, |transition| transition.duration_ticks())
+    }
+
+    pub fn insert(&mut self, position: usize, transition: Transition) {
+        self.queue[position % LED_STATE_QUEUE_SIZE] = Some(transition);
+        self.flows[position % LED_STATE_QUEUE_SIZE] = Flow::default();
     }
 
     pub fn remove(&mut self, position: usize) {
-        self.queue[position] = Box::new(move |_: usize| {
-            TransitionResult::Finished((position + 1) % LED_STATE_QUEUE_SIZE)
-        });
+        self.queue[position % LED_STATE_QUEUE_SIZE] = None;
+        self.flows[position % LED_STATE_QUEUE_SIZE] = Flow::default();
+    }
+
+    /// Adding or removing the state of the slot resets its flow, so empty slots keep the default
+    pub fn set_flow(&mut self, position: usize, flow: Flow) {
+        let position = position % LED_STATE_QUEUE_SIZE;
+        if self.queue[position].is_some() {
+            self.flows[position] = flow;
+        }
+    }
+
+    pub fn states(&self) -> impl Iterator<Item = (usize, &Transition, &Flow)> {
+        self.queue
+            .iter()
+            .zip(&self.flows)
+            .enumerate()
+            .filter_map(|(i, (slot, flow))| slot.as_ref().map(|t| (i, t, flow)))
     }
 
     pub fn restart(&mut self) {
         self.current_element = 0;
+        self.plays = 0;
     }
 
     pub fn clear(&mut self) {
-        self.queue = Vec::new();
+        self.queue = [None; LED_STATE_QUEUE_SIZE];
+        self.flows = [Flow::default(); LED_STATE_QUEUE_SIZE];
         self.current_element = 0;
+        self.plays = 0;
     }
 }
 
+/// Ticks are milliseconds
+fn ticks_between(earlier: Instant, later: Instant) -> usize {
+    later.saturating_duration_since(earlier).as_millis() as usize
+}
+
+/// Level of [`LedState`] at full brightness
+pub const MAX_LEVEL: u16 = u16::MAX;
+
+/// Colour of a led at a logical brightness `level`. The level has a finer resolution than the
+/// 5 bit global brightness of the leds, it is split between the global brightness and the
+/// colour channels when the state is written to the led, see [`LedEncoder`].
 #[derive(Clone, Copy, Default, Debug)]
 pub struct LedState {
-    pub brightness: u8,
-    pub b: u8,
-    pub g: u8,
+    pub level: u16,
     pub r: u8,
+    pub g: u8,
+    pub b: u8,
 }
 
 impl LedState {
+    /// `brightness` is the 5 bit brightness of the transitions
     pub fn new(brightness: u8, colour: &Colour) -> Self {
+        Self::with_level(level_from_brightness(brightness), colour)
+    }
+
+    pub fn with_level(level: u16, colour: &Colour) -> Self {
         Self {
-            brightness: brightness | 0b11100000,
-            b: colour.blue,
-            g: colour.green,
+            level,
             r: colour.red,
+            g: colour.green,
+            b: colour.blue,
         }
     }
 }
+
+/// Level of a 5 bit brightness, the upper 3 bits are ignored
+pub fn level_from_brightness(brightness: u8) -> u16 {
+    ((brightness & 0b00011111) as u32 * MAX_LEVEL as u32 / 0b00011111) as u16
+}
+
+/// Fractional bits of the channels carried over to the next refresh by the dithering. More bits
+/// would make the dithering cycles long enough to be seen as flicker.
+const DITHERING_BITS: u32 = 3;
+
+/// Writes the intensities of the red, green and blue channels (out of [`MAX_LEVEL`]) as the
+/// 5 bit global brightness and the 8 bit channels of an APA102 led.
+///
+/// The global brightness is the lowest one able to show the brightest channel, leaving the
+/// channels as many steps as possible - at the lowest global brightness a channel has 31 times
+/// finer steps than at the highest. With dithering, the part of a channel that falls between
+/// two steps is carried over to the next refreshes, so that it is shown on average.
+#[derive(Clone, Copy, Debug, Default)]
+struct LedEncoder {
+    // Fractions of a step of each channel, in `DITHERING_BITS`
+    remainders: [u32; 3],
+}
+
+impl LedEncoder {
+    /// Global brightness (with the 3 high bits set) followed by blue, green and red
+    fn encode(&mut self, intensity: [u16; 3], dithering: bool) -> [u8; 4] {
+        let brightest = intensity.into_iter().max().unwrap_or(0) as u32;
+        if brightest == 0 {
+            self.remainders = [0; 3];
+            return [0b11100000, 0, 0, 0];
+        }
+        let global = (brightest * 0b00011111).div_ceil(MAX_LEVEL as u32);
+        let divisor = (global * MAX_LEVEL as u32) as u64;
+        let [r, g, b] = core::array::from_fn(|i| {
+            let steps = ((intensity[i] as u64 * 0b00011111 * 255) << DITHERING_BITS) / divisor;
+            let value = if dithering {
+                let steps = steps as u32 + self.remainders[i];
+                self.remainders[i] = steps & ((1 << DITHERING_BITS) - 1);
+                steps >> DITHERING_BITS
+            } else {
+                (steps as u32 + (1 << (DITHERING_BITS - 1))) >> DITHERING_BITS
+            };
+            value.min(255) as u8
+        });
+        [global as u8 | 0b11100000, b, g, r]
+    }
+}
@@ -0,0 +1,488 @@
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::future::pending;
+
+use crate::board::{Board, ButtonEvent};
+use crate::flow::Flow;
+use crate::request::{Batch, Request, RequestError};
+use crate::rgbleds::LED_STATE_QUEUE_SIZE;
+use crate::serial_protocol::{
+    device_info, split_payload, DeviceError, Framer, Framing, MessageAssembler, ParseError,
+    ProtocolVersion, SerialCommand, SerialMessage, PARTIAL_MESSAGE_TIMEOUT_MS,
+};
+use crate::text_protocol::{
+    event_line, is_text_mode_magic, parse_line, LineEditor, TextCommand, TextError, HELP,
+};
+use crate::transitions::{solid, Transition};
+use crate::{ButtonState, Colour, LED_COUNT};
+use defmt::*;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{with_deadline, Duration, Instant};
+use embedded_hal_async::i2c::I2c;
+use heapless::{String, Vec};
+
+pub static BUTTON_EVENTS: Channel<ThreadModeRawMutex, ButtonEvent, 16> = Channel::new();
+
+pub type SharedBoard<I2C> = Mutex<ThreadModeRawMutex, RefCell<Board<I2C>>>;
+
+/// Packet based link to the host - USB CDC-ACM on the board, a pseudo terminal in the emulator
+#[allow(async_fn_in_trait)]
+pub trait SerialTransport {
+    type Error;
+
+    /// Wait for the next packet of at most 64 bytes and return its length
+    async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, Self::Error>;
+
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// The host asked for a device reset, returned by `serial_loop` after the request is acknowledged
+pub struct ResetRequested;
+
+/// Pass the button events of the board to the serial loop
+pub fn forward_button_events<I2C: I2c>(board: &mut Board<I2C>) {
+    while let Some(event) = board.pop_event() {
+        // Events are dropped if the serial loop does not keep up
+        let _ = BUTTON_EVENTS.try_send(event);
+    }
+}
+
+/// Settings every serial connection starts with, regardless of what the previous one changed
+pub fn apply_connection_defaults<I2C: I2c>(board: &mut Board<I2C>) {
+    board.unlock_led_states();
+    board.enable_keyboard_input();
+    (0..LED_COUNT).for_each(|led| {
+        board.add_led_state(
+            led,
+            0,
+            solid(0x00, Colour::white(), 0, 0),
+            &ButtonState::Idle,
+        );
+    });
+}
+
+/// State of a serial connection, reset on every reconnect
+struct Session {
+    // Every connection starts with protocol v1 until the host requests otherwise
+    framing: Framing,
+    // Button events are only sent after the host asks for them
+    events_enabled: bool,
+    batch: Option<Batch>,
+    // `Some` while in the text mode
+    text: Option<LineEditor>,
+    // Set by the `reset` line, the loop returns once the response is sent
+    reset_requested: bool,
+}
+
+/// Serve the protocol until the transport fails or the host requests a reset
+pub async fn serial_loop<T: SerialTransport, I2C: I2c>(
+    class: &mut T,
+    board: &SharedBoard<I2C>,
+) -> Result<ResetRequested, T::Error> {
+    let mut packet = [0; 64];
+    let mut framer = Framer::new();
+    let mut assembler = MessageAssembler::new();
+    let mut session = Session {
+        framing: Framing::new(),
+        events_enabled: false,
+        batch: None,
+        text: None,
+        reset_requested: false,
+    };
+    // Drop the events registered while disconnected
+    while BUTTON_EVENTS.try_receive().is_ok() {}
+    let mut received_at = Instant::now();
+    loop {
+        let read = async {
+            if framer.is_empty() && !assembler.in_progress() {
+                Ok(class.read_packet(&mut packet).await)
+            } else {
+                // Part of a frame or of a chain is already received, wait for the rest only for a
+                // limited time since the last received bytes - the events sent in the meantime
+                // do not extend it
+                with_deadline(
+                    received_at + Duration::from_millis(PARTIAL_MESSAGE_TIMEOUT_MS),
+                    class.read_packet(&mut packet),
+                )
+                .await
+            }
+        };
+        let event = async {
+            if session.events_enabled {
+                BUTTON_EVENTS.receive().await
+            } else {
+                pending().await
+            }
+        };
+        let input = select(read, event).await;
+        let n = match input {
+            Either::First(Ok(n)) => {
+                received_at = Instant::now();
+                n?
+            }
+            Either::First(Err(_)) => {
+                let partial = framer.take_partial();
+                let nack = match assembler.header() {
+                    Some(header) => {
+                        error!("Incomplete serial message chain: {}", header);
+                        SerialMessage::nack_from_request_error(
+                            header,
+                            &ParseError::InterruptedStream.into(),
+                        )
+                    }
+                    None => {
+                        error!("Incomplete serial message: {:x}", partial.as_slice());
+                        SerialMessage::nack_from_partial(&partial)
+                    }
+                };
+                assembler.reset();
+                send_message(class, &session.framing, nack).await?;
+                continue;
+            }
+            Either::Second(event) => {
+                match &session.text {
+                    Some(editor) => {
+                        send_text(class, event_line(&event).as_bytes()).await?;
+                        // Print the prompt and the interrupted line again
+                        send_text(class, b"> ").await?;
+                        send_text(class, editor.current()).await?;
+                    }
+                    None => {
+                        send_message(class, &session.framing, SerialMessage::button_event(&event))
+                            .await?
+                    }
+                }
+                continue;
+            }
+        };
+        debug!("Received {} bytes: {:x}", n, packet[0..n]);
+        if session.text.is_none()
+            && framer.is_empty()
+            && packet[0..n]
+                .first()
+                .is_some_and(|&byte| is_text_mode_magic(byte))
+        {
+            info!("Switching to the text mode");
+            assembler.reset();
+            session.text = Some(LineEditor::new());
+            send_text(
+                class,
+                b"pico-soundboard text mode, type `help` for the list of commands",
+            )
+            .await?;
+        }
+        if session.text.is_some() {
+            handle_text_input(class, &mut session, &packet[0..n], board).await?;
+            if session.reset_requested {
+                return Ok(ResetRequested);
+            }
+            continue;
+        }
+
+        if let Err(err) = framer.push(&packet[0..n]) {
+            error!("Serial receive buffer overflow");
+            send_message(class, &session.framing, SerialMessage::nack_from_error(err)).await?;
+            continue;
+        }
+
+        while let Some(frame) = framer.next_frame(session.framing.version()) {
+            let decoded = match frame {
+                Ok(frame) => session.framing.decode(&frame),
+                Err(err) => Err(err),
+            };
+            match decoded {
+                Ok(sm) => {
+                    info!("Received message: {}", sm);
+                    let sequence = match session.framing.version() {
+                        ProtocolVersion::V1 => None,
+                        ProtocolVersion::V2 => Some(session.framing.sequence()),
+                    };
+                    if let Some(header) = assembler.interrupted_by(&sm, sequence) {
+                        // The chain is dropped, the new message is handled as usual
+                        error!("Serial message chain interrupted by: {}", sm);
+                        let nack = SerialMessage::nack_from_request_error(
+                            header,
+                            &ParseError::InterruptedStream.into(),
+                        );
+                        // Respond to the dropped chain, not to the new message
+                        let mut framing = session.framing;
+                        if let Some(sequence) = assembler.sequence() {
+                            framing.set_sequence(sequence);
+                        }
+                        send_message(class, &framing, nack).await?;
+                    }
+                    let message = match assembler.push(sm, sequence) {
+                        Ok(Some(message)) => message,
+                        // Wait for the rest of the chain
+                        Ok(None) => continue,
+                        Err(err) => {
+                            error!("Failed to assemble serial message: {}", err);
+                            send_message(
+                                class,
+                                &session.framing,
+                                SerialMessage::nack_from_error(err),
+                            )
+                            .await?;
+                            continue;
+                        }
+                    };
+                    let sm = message.header();
+                    let request = match Request::try_from(&message) {
+                        Ok(request) => request,
+                        Err(err) => {
+                            error!("Rejected serial message: {}", err);
+                            send_message(
+                                class,
+                                &session.framing,
+                                SerialMessage::nack_from_request_error(sm, &err),
+                            )
+                            .await?;
+                            continue;
+                        }
+                    };
+                    let framing = &session.framing;
+                    match request {
+                        Request::SyncRequest => {
+                            send_sync_frames(class, framing, board).await?;
+                            send_message(class, framing, SerialMessage::ack_to(sm)).await?;
+                        }
+                        Request::ProtocolHandshake { version } => {
+                            let version = version.unwrap_or(framing.version());
+                            // The response is sent using the previous version
+                            send_message(
+                                class,
+                                framing,
+                                SerialMessage::handshake_response(version),
+                            )
+                            .await?;
+                            info!("Using protocol version {}", version);
+                            session.framing.set_version(version);
+                        }
+                        Request::DeviceInfo => {
+                            let payload = device_info(framing.version());
+                            for frame in split_payload(SerialCommand::DeviceInfo, &payload) {
+                                send_message(class, framing, frame).await?;
+                            }
+                        }
+                        Request::DeviceReset => {
+                            send_message(class, framing, SerialMessage::ack_to(sm)).await?;
+                            return Ok(ResetRequested);
+                        }
+                        Request::Response(command) => {
+                            // Responding to a response could make both sides loop forever
+                            warn!("Ignoring unexpected response from the host: {}", command);
+                        }
+                        request => {
+                            let response = match handle_request(&mut session, request, board).await
+                            {
+                                Ok(()) => SerialMessage::ack_to(sm),
+                                Err(err) => SerialMessage::nack_from_request_error(sm, &err),
+                            };
+                            send_message(class, &session.framing, response).await?;
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("Failed to parse serial message: {}", err);
+                    send_message(class, &session.framing, SerialMessage::nack_from_error(err))
+                        .await?
+                }
+            }
+        }
+    }
+}
+
+/// Handle a request answered only with a success or an error, the same way in both modes
+async fn handle_request<I2C: I2c>(
+    session: &mut Session,
+    request: Request,
+    board: &SharedBoard<I2C>,
+) -> Result<(), RequestError> {
+    match request {
+        Request::EnableButtonEvents => session.events_enabled = true,
+        Request::DisableButtonEvents => session.events_enabled = false,
+        Request::BeginBatch => {
+            if session.batch.is_some() {
+                return Err(DeviceError::BatchInProgress.into());
+            }
+            session.batch = Some(Batch::new());
+        }
+        Request::CommitBatch => {
+            let staged = session.batch.take().ok_or(DeviceError::NoBatchInProgress)?;
+            staged.commit(board.lock().await.get_mut());
+        }
+        Request::AbortBatch => {
+            session.batch.take().ok_or(DeviceError::NoBatchInProgress)?;
+        }
+        request => match &mut session.batch {
+            Some(staged) if request.is_state_change() => staged.stage(request)?,
+            _ => request.apply(board.lock().await.get_mut()),
+        },
+    }
+    Ok(())
+}
+
+/// Edit the typed line and handle it once complete
+async fn handle_text_input<T: SerialTransport, I2C: I2c>(
+    class: &mut T,
+    session: &mut Session,
+    bytes: &[u8],
+    board: &SharedBoard<I2C>,
+) -> Result<(), T::Error> {
+    // Backspace is echoed as 3 bytes
+    let mut echo: Vec<u8, 192> = Vec::new();
+    for &byte in bytes {
+        // The rest of the packet is dropped after switching back to the binary mode or a reset
+        if session.reset_requested {
+            break;
+        }
+        let Some(editor) = &mut session.text else {
+            break;
+        };
+        if editor.push(byte, &mut echo) {
+            let line = editor.take_line();
+            send_text(class, &echo).await?;
+            echo.clear();
+            handle_text_line(class, session, line.trim(), board).await?;
+        }
+    }
+    send_text(class, &echo).await
+}
+
+async fn handle_text_line<T: SerialTransport, I2C: I2c>(
+    class: &mut T,
+    session: &mut Session,
+    line: &str,
+    board: &SharedBoard<I2C>,
+) -> Result<(), T::Error> {
+    if !line.is_empty() {
+        info!("Received line: {}", line);
+        let result = match parse_line(line) {
+            Ok(TextCommand::Help) => {
+                send_text(class, HELP.as_bytes()).await?;
+                Ok(())
+            }
+            Ok(TextCommand::Binary) => {
+                info!("Switching to the binary mode");
+                session.text = None;
+                return send_text(class, b"ok\r\n").await;
+            }
+            Ok(TextCommand::Request(Request::DeviceReset)) => {
+                session.reset_requested = true;
+                return send_text(class, b"ok\r\n").await;
+            }
+            Ok(TextCommand::Request(request)) => handle_request(session, request, board)
+                .await
+                .map_err(TextError::from),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => send_text(class, b"ok\r\n").await?,
+            Err(err) => {
+                warn!("Rejected line: {}", err);
+                let mut response: String<128> = String::new();
+                let _ = core::write!(response, "error: {}\r\n", err);
+                send_text(class, response.as_bytes()).await?;
+            }
+        }
+    }
+    send_text(class, b"> ").await
+}
+
+// Stream the whole device state as one message (or chain) per record, the final ACK (sent by the
+// caller) ends the stream
+async fn send_sync_frames<T: SerialTransport, I2C: I2c>(
+    class: &mut T,
+    framing: &Framing,
+    board: &SharedBoard<I2C>,
+) -> Result<(), T::Error> {
+    let (keyboard_input_enabled, flags) = {
+        let mut _board = board.lock().await;
+        let _board = _board.get_mut();
+        (_board.keyboard_input_enabled(), _board.flags())
+    };
+    send_message(
+        class,
+        framing,
+        SerialMessage::sync_device(
+            keyboard_input_enabled,
+            LED_COUNT as u8,
+            LED_STATE_QUEUE_SIZE as u8,
+            flags,
+        ),
+    )
+    .await?;
+
+    for led_idx in 0..LED_COUNT {
+        // The board is only locked to copy the next record, not while it is sent
+        let status = {
+            let mut _board = board.lock().await;
+            _board.get_mut().led_status(led_idx)
+        };
+        send_message(
+            class,
+            framing,
+            SerialMessage::sync_led(led_idx as u8, &status),
+        )
+        .await?;
+        for for_state in [ButtonState::Idle, ButtonState::Pressed] {
+            let mut from_slot = 0;
+            while let Some((state_idx, transition, flow)) =
+                next_led_state(board, led_idx, &for_state, from_slot).await
+            {
+                // Longer transitions take a chain of frames, exactly as sent by the host
+                let payload = transition.to_payload(led_idx, &for_state, state_idx);
+                for frame in split_payload(SerialCommand::AddState, &payload) {
+                    send_message(class, framing, frame).await?;
+                }
+                // Followed by the flow of the slot, unless it is the default
+                if flow != Flow::default() {
+                    let frame = SerialMessage::new(
+                        SerialCommand::SetStateFlow,
+                        flow.to_data(led_idx, &for_state, state_idx),
+                        SerialCommand::EndOfStream,
+                    );
+                    send_message(class, framing, frame).await?;
+                }
+                from_slot = state_idx + 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// First occupied slot of a state queue starting at `from_slot`, copied out of the board
+async fn next_led_state<I2C: I2c>(
+    board: &SharedBoard<I2C>,
+    led_idx: usize,
+    for_state: &ButtonState,
+    from_slot: usize,
+) -> Option<(usize, Transition, Flow)> {
+    let mut _board = board.lock().await;
+    let state = _board
+        .get_mut()
+        .led_states(led_idx, for_state)
+        .find(|(state_idx, ..)| *state_idx >= from_slot)
+        .map(|(state_idx, transition, flow)| (state_idx, *transition, *flow));
+    state
+}
+
+async fn send_message<T: SerialTransport>(
+    class: &mut T,
+    framing: &Framing,
+    message: SerialMessage,
+) -> Result<(), T::Error> {
+    let bytes = framing.encode(&message);
+    info!("Sending message: {}", message);
+    class.write_packet(&bytes).await
+}
+
+async fn send_text<T: SerialTransport>(class: &mut T, text: &[u8]) -> Result<(), T::Error> {
+    for chunk in text.chunks(64) {
+        class.write_packet(chunk).await?;
+    }
+    Ok(())
+}
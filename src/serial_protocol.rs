@@ -1,6 +1,63 @@
 use defmt::Format;
+use heapless::{Deque, Vec};
 
-#[derive(Format)]
+use crate::{
+    board::ButtonEvent,
+    request::RequestError,
+    rgbleds::{LedStatus, LED_STATE_QUEUE_SIZE},
+    transitions::SUPPORTED_TRANSITIONS,
+    BUTTON_COUNT, LED_COUNT,
+};
+
+/// Maximum length of the data carried by a chain of messages
+pub const MAX_PAYLOAD_SIZE: usize = 256;
+
+/// Size of a frame in protocol v1: command, 8 data bytes and end byte
+pub const V1_FRAME_SIZE: usize = 10;
+/// Size of a frame in protocol v2: v1 frame with a sequence number and a checksum
+pub const V2_FRAME_SIZE: usize = 12;
+
+/// Size of the receive buffer of [`Framer`] - a full USB packet and a partial frame
+pub const FRAMER_BUFFER_SIZE: usize = 128;
+
+/// If an incomplete frame is not completed in this time, it is rejected
+pub const PARTIAL_MESSAGE_TIMEOUT_MS: u64 = 250;
+
+/// Features of the protocol reported in `DeviceInfo`, the value is the bit in the bitmap
+#[derive(Format, Clone, Copy)]
+#[repr(u8)]
+pub enum Capability {
+    SyncRequest = 0,
+    MultiFrameMessages = 1,
+    ProtocolV2 = 2,
+    ButtonEvents = 3,
+    Batches = 4,
+    TextMode = 5,
+    Easing = 6,
+    Calibration = 7,
+    /// Repeat counts and branches of the state queues, and flags
+    StateFlow = 8,
+    /// Brightness finer than the 5 bits of the leds, optionally dithered
+    Dithering = 9,
+}
+
+pub const CAPABILITIES: [Capability; 10] = [
+    Capability::SyncRequest,
+    Capability::MultiFrameMessages,
+    Capability::ProtocolV2,
+    Capability::ButtonEvents,
+    Capability::Batches,
+    Capability::TextMode,
+    Capability::Easing,
+    Capability::Calibration,
+    Capability::StateFlow,
+    Capability::Dithering,
+];
+
+pub const SUPPORTED_PROTOCOL_VERSIONS: [ProtocolVersion; 2] =
+    [ProtocolVersion::V1, ProtocolVersion::V2];
+
+#[derive(Format, Clone)]
 pub struct SerialMessage {
     command: SerialCommand,
     data: [u8; 8],
@@ -57,6 +114,34 @@ impl SerialMessage {
         }
     }
 
+    /// NACK with the reason as the first data byte, followed by the first 7 bytes of the
+    /// rejected message
+    pub fn nack_from_request_error(other: &SerialMessage, error: &RequestError) -> Self {
+        let (command, reason) = match error {
+            RequestError::InvalidCommand => {
+                return SerialMessage::nack_to_message(other, NackType::InvalidCommand)
+            }
+            RequestError::Parse(parse_error) => (SerialCommand::NackParseError, *parse_error as u8),
+            RequestError::Device(device_error) => {
+                (SerialCommand::NackDeviceError, *device_error as u8)
+            }
+        };
+        SerialMessage {
+            command,
+            data: [
+                reason,
+                other.command as u8,
+                other.data[0],
+                other.data[1],
+                other.data[2],
+                other.data[3],
+                other.data[4],
+                other.data[5],
+            ],
+            end_byte: SerialCommand::EndOfStream,
+        }
+    }
+
     pub fn nack_from_error(parse_error: ParseError) -> Self {
         let command = SerialCommand::NackParseError;
         let data = [parse_error as u8, 0, 0, 0, 0, 0, 0, 0];
@@ -69,6 +154,101 @@ impl SerialMessage {
         }
     }
 
+    /// First frame of a `SyncRequest` response, describing the device as a whole
+    pub fn sync_device(
+        keyboard_input_enabled: bool,
+        led_count: u8,
+        queue_size: u8,
+        flags: u8,
+    ) -> Self {
+        SerialMessage {
+            command: SerialCommand::SyncRequest,
+            data: [
+                SyncRecord::Device as u8,
+                keyboard_input_enabled as u8,
+                led_count,
+                queue_size,
+                flags,
+                0,
+                0,
+                0,
+            ],
+            end_byte: SerialCommand::EndOfStream,
+        }
+    }
+
+    /// Frame of a `SyncRequest` response describing a single led
+    pub fn sync_led(led_idx: u8, status: &LedStatus) -> Self {
+        SerialMessage {
+            command: SerialCommand::SyncRequest,
+            data: [
+                SyncRecord::Led as u8,
+                led_idx,
+                status.button_state as u8,
+                match status.lock_state {
+                    Some(state) => state as u8,
+                    None => SYNC_UNLOCKED,
+                },
+                status.idle_element as u8,
+                status.pressed_element as u8,
+                0,
+                0,
+            ],
+            end_byte: SerialCommand::EndOfStream,
+        }
+    }
+
+    /// Response to `ProtocolHandshake` - the protocol version in use after the handshake followed
+    /// by all supported versions
+    pub fn handshake_response(selected: ProtocolVersion) -> Self {
+        let mut data = [0; 8];
+        data[0] = selected as u8;
+        SUPPORTED_PROTOCOL_VERSIONS
+            .iter()
+            .enumerate()
+            .for_each(|(i, version)| data[i + 1] = *version as u8);
+        SerialMessage {
+            command: SerialCommand::ProtocolHandshake,
+            data,
+            end_byte: SerialCommand::EndOfStream,
+        }
+    }
+
+    /// Unsolicited notification about a button press or release
+    pub fn button_event(event: &ButtonEvent) -> Self {
+        // Milliseconds since boot, wrapping after ~49 days
+        let timestamp = (event.timestamp_ms as u32).to_be_bytes();
+        SerialMessage {
+            command: SerialCommand::ButtonEvent,
+            data: [
+                (event.state as u8) << 7 | (event.led_idx & 0b00001111),
+                timestamp[0],
+                timestamp[1],
+                timestamp[2],
+                timestamp[3],
+                0,
+                0,
+                0,
+            ],
+            end_byte: SerialCommand::EndOfStream,
+        }
+    }
+
+    /// NACK for an incomplete message, containing its first 8 received bytes
+    pub fn nack_from_partial(bytes: &[u8]) -> Self {
+        let mut data = [0; 8];
+        bytes
+            .iter()
+            .take(8)
+            .enumerate()
+            .for_each(|(i, byte)| data[i] = *byte);
+        SerialMessage {
+            command: SerialCommand::NackParseError,
+            data,
+            end_byte: SerialCommand::EndOfStream,
+        }
+    }
+
     pub fn to_bytes(&self) -> [u8; 10] {
         [
             self.command as u8,
@@ -97,6 +277,444 @@ impl SerialMessage {
     }
 }
 
+/// Split a payload into a chain of messages - the inverse of [`MessageAssembler`]
+pub fn split_payload(
+    command: SerialCommand,
+    payload: &[u8],
+) -> impl Iterator<Item = SerialMessage> + '_ {
+    let frames = payload.len().div_ceil(8).max(1);
+    (0..frames).map(move |i| {
+        let mut data = [0; 8];
+        payload
+            .iter()
+            .skip(i * 8)
+            .take(8)
+            .enumerate()
+            .for_each(|(j, byte)| data[j] = *byte);
+        SerialMessage {
+            command,
+            data,
+            end_byte: if i + 1 == frames {
+                SerialCommand::EndOfStream
+            } else {
+                SerialCommand::ToBeContinued
+            },
+        }
+    })
+}
+
+/// Payload of the `DeviceInfo` response
+pub fn device_info(protocol_version: ProtocolVersion) -> Vec<u8, 32> {
+    let capabilities = CAPABILITIES
+        .iter()
+        .fold(0u32, |bitmap, capability| bitmap | 1 << *capability as u8);
+    let mut payload = Vec::new();
+    let _ = payload.extend_from_slice(&[
+        env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+        env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+        env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+        protocol_version as u8,
+        SUPPORTED_PROTOCOL_VERSIONS
+            .iter()
+            .fold(0, |max, version| max.max(*version as u8)),
+        BUTTON_COUNT as u8,
+        LED_COUNT as u8,
+        LED_STATE_QUEUE_SIZE as u8,
+    ]);
+    let _ = payload.extend_from_slice(&capabilities.to_be_bytes());
+    let _ = payload.push(SUPPORTED_TRANSITIONS.len() as u8);
+    SUPPORTED_TRANSITIONS.iter().for_each(|kind| {
+        let _ = payload.push(*kind as u8);
+    });
+    payload
+}
+
+/// Reassembles chains of messages ending with `ToBeContinued` into a single payload.
+///
+/// Frames of a chain are not acknowledged separately - the whole chain is either ACKed after
+/// its last frame (ending with `EndOfStream`) or NACKed as soon as an error is detected, in
+/// which case the chain is dropped and has to be resent from the start. A frame with another
+/// command, or in protocol v2 a frame that is not numbered right after the previous one, drops
+/// the chain as well and starts a new one, see [`MessageAssembler::interrupted_by`].
+pub struct MessageAssembler {
+    header: Option<SerialMessage>,
+    payload: Vec<u8, MAX_PAYLOAD_SIZE>,
+    // Sequence number of the last frame of the chain in progress, `None` in protocol v1
+    sequence: Option<u8>,
+}
+
+impl MessageAssembler {
+    pub fn new() -> Self {
+        MessageAssembler {
+            header: None,
+            payload: Vec::new(),
+            sequence: None,
+        }
+    }
+
+    /// Feed the next received message along with its sequence number in protocol v2, returns the
+    /// full message if it was the last frame
+    pub fn push(
+        &mut self,
+        message: SerialMessage,
+        sequence: Option<u8>,
+    ) -> Result<Option<AssembledMessage>, ParseError> {
+        if self.interrupted_by(&message, sequence).is_some() {
+            self.reset();
+        }
+        let continued = match message.end_byte {
+            SerialCommand::EndOfStream => false,
+            SerialCommand::ToBeContinued => true,
+            _ => {
+                self.reset();
+                return Err(ParseError::InvalidEndByte);
+            }
+        };
+        if self.payload.extend_from_slice(&message.data).is_err() {
+            self.reset();
+            return Err(ParseError::PayloadTooLong);
+        }
+        if self.header.is_none() {
+            self.header = Some(message);
+        }
+        self.sequence = sequence;
+
+        if continued {
+            Ok(None)
+        } else {
+            let assembled = AssembledMessage {
+                header: self.header.take().unwrap(),
+                payload: core::mem::take(&mut self.payload),
+            };
+            self.sequence = None;
+            Ok(Some(assembled))
+        }
+    }
+
+    /// Drop the chain that is currently being assembled
+    pub fn reset(&mut self) {
+        self.header = None;
+        self.payload.clear();
+        self.sequence = None;
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.header.is_some()
+    }
+
+    /// First frame of the chain in progress if `message` does not continue it, pushing `message`
+    /// drops that chain.
+    ///
+    /// A message continues the chain if it has the same command and, in protocol v2, is numbered
+    /// with the sequence number following the previous frame. In protocol v1 a chain that lost a
+    /// frame is only dropped by a message with another command or by the timeout, a message with
+    /// the same command is appended to it.
+    pub fn interrupted_by(
+        &self,
+        message: &SerialMessage,
+        sequence: Option<u8>,
+    ) -> Option<&SerialMessage> {
+        let consecutive = match (self.sequence, sequence) {
+            (Some(previous), Some(sequence)) => sequence == previous.wrapping_add(1),
+            _ => true,
+        };
+        self.header
+            .as_ref()
+            .filter(|header| header.command as u8 != message.command as u8 || !consecutive)
+    }
+
+    /// First frame of the chain in progress
+    pub fn header(&self) -> Option<&SerialMessage> {
+        self.header.as_ref()
+    }
+
+    /// Sequence number of the last received frame of the chain in progress, in protocol v2
+    pub fn sequence(&self) -> Option<u8> {
+        self.sequence
+    }
+}
+
+/// Single frame message, e.g. built from a line in the text mode
+impl From<SerialMessage> for AssembledMessage {
+    fn from(header: SerialMessage) -> Self {
+        AssembledMessage {
+            payload: Vec::from_slice(&header.data).unwrap(),
+            header,
+        }
+    }
+}
+
+impl Default for MessageAssembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Message reassembled from one or more frames
+pub struct AssembledMessage {
+    header: SerialMessage,
+    payload: Vec<u8, MAX_PAYLOAD_SIZE>,
+}
+
+impl AssembledMessage {
+    /// Message as it would be reassembled from the chain sent by [`split_payload`], e.g. built
+    /// from a line in the text mode
+    pub fn from_payload(command: SerialCommand, payload: &[u8]) -> Self {
+        let mut frames = split_payload(command, payload);
+        // There is always at least one frame, even for an empty payload
+        let mut assembled: AssembledMessage = frames.next().unwrap().into();
+        frames.for_each(|frame| {
+            let _ = assembled.payload.extend_from_slice(&frame.data);
+        });
+        assembled
+    }
+
+    /// First frame of the chain, ACKs and NACKs are sent in response to it
+    pub fn header(&self) -> &SerialMessage {
+        &self.header
+    }
+
+    pub fn get_command(&self) -> &SerialCommand {
+        &self.header.command
+    }
+
+    /// Data bytes of all frames of the chain
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn is_multi_frame(&self) -> bool {
+        self.payload.len() > self.header.data.len()
+    }
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum ProtocolVersion {
+    V1 = 0x1,
+    V2 = 0x2,
+}
+
+impl ProtocolVersion {
+    pub fn frame_size(&self) -> usize {
+        match self {
+            ProtocolVersion::V1 => V1_FRAME_SIZE,
+            ProtocolVersion::V2 => V2_FRAME_SIZE,
+        }
+    }
+}
+
+impl TryFrom<u8> for ProtocolVersion {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x1 => Ok(ProtocolVersion::V1),
+            0x2 => Ok(ProtocolVersion::V2),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Encodes and decodes messages according to the negotiated protocol version.
+///
+/// In v2, each frame is prefixed with a sequence number (sent right after the command byte) and
+/// suffixed with a [`crc8`] of all preceding bytes. Responses carry the sequence number of the
+/// last received frame.
+#[derive(Format, Clone, Copy)]
+pub struct Framing {
+    version: ProtocolVersion,
+    sequence: u8,
+}
+
+impl Framing {
+    pub fn new() -> Self {
+        Framing {
+            version: ProtocolVersion::V1,
+            sequence: 0,
+        }
+    }
+
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    pub fn frame_size(&self) -> usize {
+        self.version.frame_size()
+    }
+
+    /// Sequence number of the last decoded frame, sent with every encoded frame
+    pub fn sequence(&self) -> u8 {
+        self.sequence
+    }
+
+    /// Used by the host to number its requests
+    pub fn set_sequence(&mut self, sequence: u8) {
+        self.sequence = sequence;
+    }
+
+    pub fn decode(&mut self, bytes: &[u8]) -> Result<SerialMessage, ParseError> {
+        match self.version {
+            ProtocolVersion::V1 => SerialMessage::try_from(bytes),
+            ProtocolVersion::V2 => {
+                if bytes.len() != V2_FRAME_SIZE {
+                    return Err(ParseError::InvalidMessageLength);
+                }
+                // Respond with the received sequence number even if the frame is corrupted
+                self.sequence = bytes[1];
+                if crc8(&bytes[..V2_FRAME_SIZE - 1]) != bytes[V2_FRAME_SIZE - 1] {
+                    return Err(ParseError::InvalidChecksum);
+                }
+                let mut v1_bytes = [0; V1_FRAME_SIZE];
+                v1_bytes[0] = bytes[0];
+                v1_bytes[1..].copy_from_slice(&bytes[2..V2_FRAME_SIZE - 1]);
+                SerialMessage::try_from(v1_bytes.as_slice())
+            }
+        }
+    }
+
+    pub fn encode(&self, message: &SerialMessage) -> Vec<u8, V2_FRAME_SIZE> {
+        let bytes = message.to_bytes();
+        let mut frame = Vec::new();
+        match self.version {
+            ProtocolVersion::V1 => {
+                let _ = frame.extend_from_slice(&bytes);
+            }
+            ProtocolVersion::V2 => {
+                let _ = frame.push(bytes[0]);
+                let _ = frame.push(self.sequence);
+                let _ = frame.extend_from_slice(&bytes[1..]);
+                let _ = frame.push(crc8(&frame));
+            }
+        }
+        frame
+    }
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Splits a stream of bytes into frames, regardless of how they were split into USB packets.
+///
+/// A frame has to start with a valid command byte and have a valid end byte at the correct
+/// offset. Otherwise the framer resynchronises by discarding bytes until the next possible start
+/// of a frame.
+pub struct Framer {
+    buffer: Deque<u8, FRAMER_BUFFER_SIZE>,
+}
+
+impl Framer {
+    pub fn new() -> Self {
+        Framer {
+            buffer: Deque::new(),
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) -> Result<(), ParseError> {
+        for byte in bytes {
+            if self.buffer.push_back(*byte).is_err() {
+                self.buffer.clear();
+                return Err(ParseError::InvalidMessageLength);
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the next complete frame from the buffer. An error is returned once for every run
+    /// of discarded bytes.
+    pub fn next_frame(
+        &mut self,
+        version: ProtocolVersion,
+    ) -> Option<Result<Vec<u8, V2_FRAME_SIZE>, ParseError>> {
+        let frame_size = version.frame_size();
+        let end_byte_offset = match version {
+            ProtocolVersion::V1 => V1_FRAME_SIZE - 1,
+            ProtocolVersion::V2 => V2_FRAME_SIZE - 2,
+        };
+
+        let mut error = None;
+        loop {
+            let first = *self.buffer.front()?;
+            if SerialCommand::try_from(first).is_err() {
+                self.buffer.pop_front();
+                error.get_or_insert(ParseError::InvalidCommand);
+                continue;
+            }
+            if let Some(error) = error {
+                // Report the discarded bytes before handling the frame
+                return Some(Err(error));
+            }
+            if self.buffer.len() < frame_size {
+                return None;
+            }
+            let end_byte = *self.buffer.iter().nth(end_byte_offset).unwrap();
+            match SerialCommand::try_from(end_byte) {
+                Ok(SerialCommand::EndOfStream) | Ok(SerialCommand::ToBeContinued) => {
+                    let frame = (0..frame_size)
+                        .filter_map(|_| self.buffer.pop_front())
+                        .collect();
+                    return Some(Ok(frame));
+                }
+                _ => {
+                    // Not a frame boundary, look for the next command byte
+                    self.buffer.pop_front();
+                    error = Some(ParseError::InvalidEndByte);
+                }
+            }
+        }
+    }
+
+    /// Remove and return the incomplete frame from the buffer
+    pub fn take_partial(&mut self) -> Vec<u8, FRAMER_BUFFER_SIZE> {
+        let mut partial = Vec::new();
+        while let Some(byte) = self.buffer.pop_front() {
+            let _ = partial.push(byte);
+        }
+        partial
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+impl Default for Framer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CRC-8/SMBUS - polynomial `0x07`, initial value `0x00`, no reflection, no final XOR
+pub fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Value sent in place of a [`ButtonState`](crate::ButtonState) for leds that are not locked
+pub const SYNC_UNLOCKED: u8 = 0xff;
+
+/// Type of a `SyncRequest` response frame, sent as the first data byte
+#[derive(Format, Clone, Copy)]
+#[repr(u8)]
+pub enum SyncRecord {
+    Device = 0x0,
+    Led = 0x1,
+}
+
 #[derive(Format)]
 #[repr(u8)]
 pub enum NackType {
@@ -107,6 +725,18 @@ pub enum NackType {
     DeviceBusy,
 }
 
+impl NackType {
+    pub fn description(&self) -> &'static str {
+        match self {
+            NackType::General => "general error",
+            NackType::InvalidCommand => "invalid command",
+            NackType::NackParseError => "parse error",
+            NackType::DeviceError => "device error",
+            NackType::DeviceBusy => "device busy",
+        }
+    }
+}
+
 impl TryFrom<&[u8]> for SerialMessage {
     type Error = ParseError;
 
@@ -132,12 +762,86 @@ impl TryFrom<&[u8]> for SerialMessage {
     }
 }
 
-#[derive(Format, Debug)]
+#[derive(Format, Debug, Clone, Copy)]
 pub enum ParseError {
     InvalidCommand = 0x0,
     InvalidData = 0x1,
     InvalidEndByte = 0x2,
     InvalidMessageLength = 0x3,
+    PayloadTooLong = 0x4,
+    InterruptedStream = 0x5,
+    InvalidChecksum = 0x6,
+    InvalidTransition = 0x7,
+}
+
+impl TryFrom<u8> for ParseError {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x0 => Ok(ParseError::InvalidCommand),
+            0x1 => Ok(ParseError::InvalidData),
+            0x2 => Ok(ParseError::InvalidEndByte),
+            0x3 => Ok(ParseError::InvalidMessageLength),
+            0x4 => Ok(ParseError::PayloadTooLong),
+            0x5 => Ok(ParseError::InterruptedStream),
+            0x6 => Ok(ParseError::InvalidChecksum),
+            0x7 => Ok(ParseError::InvalidTransition),
+            _ => Err(value),
+        }
+    }
+}
+
+impl ParseError {
+    pub fn description(&self) -> &'static str {
+        match self {
+            ParseError::InvalidCommand => "invalid command",
+            ParseError::InvalidData => "invalid data",
+            ParseError::InvalidEndByte => "invalid end byte",
+            ParseError::InvalidMessageLength => "invalid message length",
+            ParseError::PayloadTooLong => "payload too long",
+            ParseError::InterruptedStream => "interrupted multi-frame stream",
+            ParseError::InvalidChecksum => "invalid checksum",
+            ParseError::InvalidTransition => "unknown or invalid transition",
+        }
+    }
+}
+
+/// Reason for `NackDeviceError` - the message is valid, but cannot be handled by the device
+#[derive(Format, Debug, Clone, Copy)]
+pub enum DeviceError {
+    InvalidLedIndex = 0x0,
+    InvalidStateIndex = 0x1,
+    BatchOverflow = 0x2,
+    BatchInProgress = 0x3,
+    NoBatchInProgress = 0x4,
+}
+
+impl TryFrom<u8> for DeviceError {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x0 => Ok(DeviceError::InvalidLedIndex),
+            0x1 => Ok(DeviceError::InvalidStateIndex),
+            0x2 => Ok(DeviceError::BatchOverflow),
+            0x3 => Ok(DeviceError::BatchInProgress),
+            0x4 => Ok(DeviceError::NoBatchInProgress),
+            _ => Err(value),
+        }
+    }
+}
+
+impl DeviceError {
+    pub fn description(&self) -> &'static str {
+        match self {
+            DeviceError::InvalidLedIndex => "invalid led index",
+            DeviceError::InvalidStateIndex => "invalid state index",
+            DeviceError::BatchOverflow => "too many requests in the batch",
+            DeviceError::BatchInProgress => "a batch is already open",
+            DeviceError::NoBatchInProgress => "no batch is open",
+        }
+    }
 }
 
 #[derive(Format, Clone, Copy)]
@@ -147,6 +851,8 @@ pub enum SerialCommand {
     ToBeContinued = 0x81,
     // Sync commands
     SyncRequest = 0x90,
+    ProtocolHandshake = 0x91,
+    DeviceInfo = 0x92,
     // Device related commands
     DeviceReset = 0xa0,
     DisableKeyboardInput = 0xa1,
@@ -155,10 +861,23 @@ pub enum SerialCommand {
     LockAllButtonStates = 0xa4,
     UnlockButtonState = 0xa5,
     UnlockAllButtonStates = 0xa6,
+    EnableButtonEvents = 0xa7,
+    DisableButtonEvents = 0xa8,
+    SetGamma = 0xa9,
+    SetColourCorrection = 0xaa,
+    SetGlobalBrightness = 0xab,
+    SetFlag = 0xac,
+    SetDithering = 0xad,
     // State related commands
     AddState = 0xb0,
     RemoveState,
     ClearStates,
+    BeginBatch,
+    CommitBatch,
+    AbortBatch,
+    SetStateFlow,
+    // Event notifications
+    ButtonEvent = 0xc0,
     // Communication related commands
     // NACK types
     NackGeneral = 0xf0,
@@ -180,6 +899,8 @@ impl TryFrom<u8> for SerialCommand {
             0x80 => Ok(SerialCommand::EndOfStream),
             0x81 => Ok(SerialCommand::ToBeContinued),
             0x90 => Ok(SerialCommand::SyncRequest),
+            0x91 => Ok(SerialCommand::ProtocolHandshake),
+            0x92 => Ok(SerialCommand::DeviceInfo),
             0xa0 => Ok(SerialCommand::DeviceReset),
             0xa1 => Ok(SerialCommand::DisableKeyboardInput),
             0xa2 => Ok(SerialCommand::EnableKeyboardInput),
@@ -187,9 +908,21 @@ impl TryFrom<u8> for SerialCommand {
             0xa4 => Ok(SerialCommand::LockAllButtonStates),
             0xa5 => Ok(SerialCommand::UnlockButtonState),
             0xa6 => Ok(SerialCommand::UnlockAllButtonStates),
+            0xa7 => Ok(SerialCommand::EnableButtonEvents),
+            0xa8 => Ok(SerialCommand::DisableButtonEvents),
+            0xa9 => Ok(SerialCommand::SetGamma),
+            0xaa => Ok(SerialCommand::SetColourCorrection),
+            0xab => Ok(SerialCommand::SetGlobalBrightness),
+            0xac => Ok(SerialCommand::SetFlag),
+            0xad => Ok(SerialCommand::SetDithering),
             0xb0 => Ok(SerialCommand::AddState),
             0xb1 => Ok(SerialCommand::RemoveState),
             0xb2 => Ok(SerialCommand::ClearStates),
+            0xb3 => Ok(SerialCommand::BeginBatch),
+            0xb4 => Ok(SerialCommand::CommitBatch),
+            0xb5 => Ok(SerialCommand::AbortBatch),
+            0xb6 => Ok(SerialCommand::SetStateFlow),
+            0xc0 => Ok(SerialCommand::ButtonEvent),
             0xf0 => Ok(SerialCommand::NackGeneral),
             0xf1 => Ok(SerialCommand::NackInvalidCommand),
             0xf2 => Ok(SerialCommand::NackParseError),
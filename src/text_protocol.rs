@@ -0,0 +1,624 @@
+use core::fmt::{self, Write};
+
+use defmt::Format;
+use heapless::{String, Vec};
+
+use crate::{
+    board::ButtonEvent,
+    calibration::MAX_GAMMA,
+    flow::{Branch, Condition, Flag, Flow},
+    request::{data_with, Request, RequestError, ALL_LEDS},
+    serial_protocol::{AssembledMessage, NackType, SerialCommand, SerialMessage},
+    transitions::{ColourSpace, Easing, TransitionKind, MAX_KEYFRAMES, MAX_TRANSITION_PAYLOAD},
+    ButtonState, Colour,
+};
+
+/// Maximum length of a single line in text mode
+pub const MAX_LINE_LENGTH: usize = 192;
+
+pub const HELP: &str = "commands:\r\n\
+    \x20 ping | reset | help | binary\r\n\
+    \x20 keyboard on|off\r\n\
+    \x20 events on|off\r\n\
+    \x20 lock all|<led> idle|pressed\r\n\
+    \x20 unlock all|<led>\r\n\
+    \x20 state add <led> idle|pressed <transition> <brightness hex> <rrggbb> <ticks> [slot=<n>] [next=<n>] [ease=<curve>]\r\n\
+    \x20 curves: linear quadratic cubic sine exponential ease_in_out\r\n\
+    \x20 crossfade options: to=<rrggbb> [to_brightness=<hex>] [space=rgb|hsv]\r\n\
+    \x20 blink options (<ticks> is the period): [off=<rrggbb>] [duty=<percent>] [repeat=<n>]\r\n\
+    \x20 hue_cycle options (<rrggbb> sets the first hue): [speed=<hues per second>]\r\n\
+    \x20 flicker options (<brightness hex> is the intensity): [speed=<changes per second>] [seed=<n>]\r\n\
+    \x20 keyframes options (<brightness hex> <rrggbb> <ticks> is the first keyframe): key=<ticks>:<brightness hex>:<rrggbb>... [repeat=<n>] [space=rgb|hsv]\r\n\
+    \x20 state remove <led> idle|pressed <slot>\r\n\
+    \x20 state clear <led> idle|pressed\r\n\
+    \x20 state flow <led> idle|pressed <slot> [repeat=<n>] [if=<condition> goto=<slot>]\r\n\
+    \x20 conditions: held:<ticks> presses:<n> flag:<n> noflag:<n>\r\n\
+    \x20 flag <n> on|off\r\n\
+    \x20 batch begin|commit|abort\r\n\
+    \x20 gamma <red> [<green> <blue>] (e.g. 2.2, 1.0 turns the correction off)\r\n\
+    \x20 correction all|<led> <rrggbb>\r\n\
+    \x20 brightness <hex>\r\n\
+    \x20 dithering on|off\r\n";
+
+/// In binary mode, a line ending received at the start of a frame switches to text mode, so
+/// pressing enter in a terminal is enough to start typing commands
+pub fn is_text_mode_magic(byte: u8) -> bool {
+    byte == b'\r' || byte == b'\n'
+}
+
+/// Line typed in a terminal, edited and echoed back byte by byte
+pub struct LineEditor {
+    line: Vec<u8, MAX_LINE_LENGTH>,
+    previous: u8,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        LineEditor {
+            line: Vec::new(),
+            previous: 0,
+        }
+    }
+
+    /// Feed a received byte, bytes to be echoed back are appended to `echo`. Returns `true` once
+    /// the line is complete and can be taken with `take_line`.
+    pub fn push<const N: usize>(&mut self, byte: u8, echo: &mut Vec<u8, N>) -> bool {
+        let previous = core::mem::replace(&mut self.previous, byte);
+        match byte {
+            // Second half of CRLF, the line was already completed by CR
+            b'\n' if previous == b'\r' => false,
+            b'\r' | b'\n' => {
+                let _ = echo.extend_from_slice(b"\r\n");
+                true
+            }
+            // Backspace or delete, depending on the terminal
+            0x08 | 0x7f => {
+                if self.line.pop().is_some() {
+                    let _ = echo.extend_from_slice(b"\x08 \x08");
+                }
+                false
+            }
+            // Printable characters, anything past the maximum line length is dropped
+            b' '..=b'~' => {
+                if self.line.push(byte).is_ok() {
+                    let _ = echo.push(byte);
+                }
+                false
+            }
+            _ => false,
+        }
+    }
+
+    pub fn take_line(&mut self) -> String<MAX_LINE_LENGTH> {
+        // Only printable ASCII characters are ever pushed
+        String::from_utf8(core::mem::take(&mut self.line)).unwrap_or_default()
+    }
+
+    /// Line typed so far, e.g. to print it again after an event
+    pub fn current(&self) -> &[u8] {
+        &self.line
+    }
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Button event printed in between the typed lines
+pub fn event_line(event: &ButtonEvent) -> String<64> {
+    let state = match event.state {
+        ButtonState::Idle => "released",
+        ButtonState::Pressed => "pressed",
+    };
+    let mut line = String::new();
+    let _ = write!(
+        line,
+        "\r\nevent: led {} {} at {} ms\r\n",
+        event.led_idx, state, event.timestamp_ms
+    );
+    line
+}
+
+pub enum TextCommand {
+    Request(Request),
+    /// Switch back to the binary protocol
+    Binary,
+    Help,
+}
+
+#[derive(Format, Debug)]
+pub enum TextError {
+    UnknownCommand,
+    MissingArgument(&'static str),
+    InvalidArgument(&'static str),
+    Request(RequestError),
+}
+
+impl From<RequestError> for TextError {
+    fn from(value: RequestError) -> Self {
+        TextError::Request(value)
+    }
+}
+
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TextError::UnknownCommand => write!(f, "unknown command, type `help` for the list"),
+            TextError::MissingArgument(name) => write!(f, "missing argument `{}`", name),
+            TextError::InvalidArgument(name) => write!(f, "invalid value of `{}`", name),
+            TextError::Request(RequestError::InvalidCommand) => {
+                write!(f, "{}", NackType::InvalidCommand.description())
+            }
+            TextError::Request(RequestError::Parse(error)) => write!(
+                f,
+                "{}: {}",
+                NackType::NackParseError.description(),
+                error.description()
+            ),
+            TextError::Request(RequestError::Device(error)) => write!(
+                f,
+                "{}: {}",
+                NackType::DeviceError.description(),
+                error.description()
+            ),
+        }
+    }
+}
+
+/// Parse a line into a request. The request is encoded as a binary message first, so that it is
+/// validated in exactly the same way as in binary mode.
+pub fn parse_line(line: &str) -> Result<TextCommand, TextError> {
+    let mut args = line.split_whitespace();
+    let (command, data) = match next_arg(&mut args, "command")? {
+        "help" => return Ok(TextCommand::Help),
+        "binary" => return Ok(TextCommand::Binary),
+        "ping" => (SerialCommand::Ping, [0; 8]),
+        "reset" => (SerialCommand::DeviceReset, [0; 8]),
+        "keyboard" => match next_arg(&mut args, "on|off")? {
+            "on" => (SerialCommand::EnableKeyboardInput, [0; 8]),
+            "off" => (SerialCommand::DisableKeyboardInput, [0; 8]),
+            _ => return Err(TextError::InvalidArgument("on|off")),
+        },
+        "events" => match next_arg(&mut args, "on|off")? {
+            "on" => (SerialCommand::EnableButtonEvents, [0; 8]),
+            "off" => (SerialCommand::DisableButtonEvents, [0; 8]),
+            _ => return Err(TextError::InvalidArgument("on|off")),
+        },
+        "lock" => match next_arg(&mut args, "led")? {
+            "all" => {
+                let state = parse_button_state(next_arg(&mut args, "state")?)?;
+                (SerialCommand::LockAllButtonStates, data_with(&[state << 7]))
+            }
+            led => {
+                let led_idx = parse_nibble(led, "led")?;
+                let state = parse_button_state(next_arg(&mut args, "state")?)?;
+                (
+                    SerialCommand::LockButtonState,
+                    data_with(&[state << 7 | led_idx]),
+                )
+            }
+        },
+        "unlock" => match next_arg(&mut args, "led")? {
+            "all" => (SerialCommand::UnlockAllButtonStates, [0; 8]),
+            led => (
+                SerialCommand::UnlockButtonState,
+                data_with(&[parse_nibble(led, "led")?]),
+            ),
+        },
+        "state" => {
+            let (command, payload) = parse_state(&mut args)?;
+            return request_from_payload(command, &payload);
+        }
+        "batch" => match next_arg(&mut args, "begin|commit|abort")? {
+            "begin" => (SerialCommand::BeginBatch, [0; 8]),
+            "commit" => (SerialCommand::CommitBatch, [0; 8]),
+            "abort" => (SerialCommand::AbortBatch, [0; 8]),
+            _ => return Err(TextError::InvalidArgument("begin|commit|abort")),
+        },
+        "gamma" => {
+            let red = parse_gamma(next_arg(&mut args, "gamma")?)?;
+            let gamma = match args.next() {
+                Some(green) => [
+                    red,
+                    parse_gamma(green)?,
+                    parse_gamma(next_arg(&mut args, "blue")?)?,
+                ],
+                None => [red; 3],
+            };
+            (SerialCommand::SetGamma, data_with(&gamma))
+        }
+        "correction" => {
+            let led_idx = match next_arg(&mut args, "led")? {
+                "all" => ALL_LEDS,
+                led => parse_nibble(led, "led")?,
+            };
+            let correction = parse_colour(next_arg(&mut args, "correction")?)
+                .ok_or(TextError::InvalidArgument("correction"))?;
+            (
+                SerialCommand::SetColourCorrection,
+                data_with(&[led_idx, correction.red, correction.green, correction.blue]),
+            )
+        }
+        "brightness" => {
+            let brightness = parse_hex(next_arg(&mut args, "brightness")?)
+                .ok_or(TextError::InvalidArgument("brightness"))?;
+            (SerialCommand::SetGlobalBrightness, data_with(&[brightness]))
+        }
+        "dithering" => match next_arg(&mut args, "on|off")? {
+            "on" => (SerialCommand::SetDithering, data_with(&[1])),
+            "off" => (SerialCommand::SetDithering, data_with(&[0])),
+            _ => return Err(TextError::InvalidArgument("on|off")),
+        },
+        "flag" => {
+            let flag = next_arg(&mut args, "flag")?
+                .parse()
+                .ok()
+                .and_then(Flag::new)
+                .ok_or(TextError::InvalidArgument("flag"))?;
+            let value = match next_arg(&mut args, "on|off")? {
+                "on" => 1,
+                "off" => 0,
+                _ => return Err(TextError::InvalidArgument("on|off")),
+            };
+            (SerialCommand::SetFlag, data_with(&[flag.index(), value]))
+        }
+        _ => return Err(TextError::UnknownCommand),
+    };
+    let message: AssembledMessage =
+        SerialMessage::new(command, data, SerialCommand::EndOfStream).into();
+    Ok(TextCommand::Request(Request::try_from(&message)?))
+}
+
+/// Request sent as a chain of messages in binary mode
+fn request_from_payload(command: SerialCommand, payload: &[u8]) -> Result<TextCommand, TextError> {
+    let message = AssembledMessage::from_payload(command, payload);
+    Ok(TextCommand::Request(Request::try_from(&message)?))
+}
+
+fn parse_state<'a>(
+    args: &mut impl Iterator<Item = &'a str>,
+) -> Result<(SerialCommand, Vec<u8, MAX_TRANSITION_PAYLOAD>), TextError> {
+    let action = next_arg(args, "add|remove|clear|flow")?;
+    let led_idx = parse_nibble(next_arg(args, "led")?, "led")?;
+    let state = parse_button_state(next_arg(args, "state")?)?;
+    match action {
+        "add" => {
+            let kind = TransitionKind::from_name(next_arg(args, "transition")?)
+                .ok_or(TextError::InvalidArgument("transition"))?;
+            let brightness = parse_hex(next_arg(args, "brightness")?)
+                .ok_or(TextError::InvalidArgument("brightness"))?;
+            let colour = parse_colour(next_arg(args, "colour")?)
+                .ok_or(TextError::InvalidArgument("colour"))?;
+            let duration: u16 = next_arg(args, "duration")?
+                .parse()
+                .map_err(|_| TextError::InvalidArgument("duration"))?;
+            let mut options = Options::parse(args)?;
+            let slot = options.take("slot", |value| parse_nibble(value, "slot").ok())?;
+            let next = options.take("next", |value| parse_nibble(value, "next").ok())?;
+            let easing = match (kind, options.take("ease", Easing::from_name)?) {
+                (
+                    TransitionKind::Solid
+                    | TransitionKind::Blink
+                    | TransitionKind::HueCycle
+                    | TransitionKind::Flicker,
+                    Some(_),
+                ) => return Err(TextError::InvalidArgument("ease")),
+                (_, easing) => easing.unwrap_or_default(),
+            };
+            let duration = duration.to_be_bytes();
+            let mut payload = Vec::new();
+            let _ = payload.extend_from_slice(&[
+                state << 7 | (kind as u8) << 4 | led_idx,
+                slot.unwrap_or(0) << 4 | next.unwrap_or(0),
+                brightness,
+                colour.red,
+                colour.green,
+                colour.blue,
+                duration[0],
+                duration[1],
+            ]);
+            match kind {
+                TransitionKind::Crossfade => {
+                    let to = options
+                        .take("to", parse_colour)?
+                        .ok_or(TextError::MissingArgument("to"))?;
+                    let to_brightness = options.take("to_brightness", parse_hex)?;
+                    let space = options.take("space", ColourSpace::from_name)?;
+                    let _ = payload.extend_from_slice(&data_with(&[
+                        to_brightness.unwrap_or(brightness),
+                        to.red,
+                        to.green,
+                        to.blue,
+                        space.unwrap_or_default() as u8,
+                        easing.code(),
+                    ]));
+                }
+                TransitionKind::FadeOut | TransitionKind::FadeIn if easing != Easing::Linear => {
+                    let _ = payload.extend_from_slice(&data_with(&[easing.code()]));
+                }
+                TransitionKind::Blink => {
+                    let off = options
+                        .take("off", parse_colour)?
+                        .unwrap_or(Colour::rgb(0, 0, 0));
+                    let duty = options
+                        .take("duty", |value| {
+                            value.parse().ok().filter(|&duty| duty <= 100)
+                        })?
+                        .unwrap_or(50);
+                    let repeat: u16 = options
+                        .take("repeat", |value| value.parse().ok())?
+                        .unwrap_or(0);
+                    let repeat = repeat.to_be_bytes();
+                    let _ = payload.extend_from_slice(&data_with(&[
+                        duty, off.red, off.green, off.blue, repeat[0], repeat[1],
+                    ]));
+                }
+                TransitionKind::HueCycle => {
+                    // The cycle starts from the hue and saturation of the colour
+                    let (hue, saturation, _) = colour.to_hsv();
+                    let [high, low] = hue.to_be_bytes();
+                    payload[3..6].copy_from_slice(&[high, low, saturation]);
+                    let speed: i16 = options
+                        .take("speed", |value| value.parse().ok())?
+                        .unwrap_or(DEFAULT_HUE_SPEED);
+                    let _ = payload.extend_from_slice(&data_with(&speed.to_be_bytes()));
+                }
+                TransitionKind::Flicker => {
+                    let speed = options
+                        .take("speed", |value| {
+                            value.parse().ok().filter(|&speed| speed > 0)
+                        })?
+                        .unwrap_or(DEFAULT_FLICKER_SPEED);
+                    let seed: u32 = options
+                        .take("seed", |value| value.parse().ok())?
+                        .unwrap_or(0);
+                    let seed = seed.to_be_bytes();
+                    let _ = payload.extend_from_slice(&data_with(&[
+                        speed, seed[0], seed[1], seed[2], seed[3],
+                    ]));
+                }
+                TransitionKind::Keyframes => {
+                    // The arguments are the first keyframe, at `duration` ticks from the start
+                    let mut keyframes: Vec<[u8; 6], MAX_KEYFRAMES> = Vec::new();
+                    let _ = keyframes.push([
+                        duration[0],
+                        duration[1],
+                        brightness,
+                        colour.red,
+                        colour.green,
+                        colour.blue,
+                    ]);
+                    while let Some(keyframe) = options.take("key", parse_keyframe)? {
+                        keyframes
+                            .push(keyframe)
+                            .map_err(|_| TextError::InvalidArgument("key"))?;
+                    }
+                    let repeat: u16 = options
+                        .take("repeat", |value| value.parse().ok())?
+                        .unwrap_or(0);
+                    let repeat = repeat.to_be_bytes();
+                    let space = options.take("space", ColourSpace::from_name)?;
+                    // The curve is sent in place of the brightness
+                    payload[2..8].copy_from_slice(&[
+                        easing.code(),
+                        keyframes.len() as u8,
+                        repeat[0],
+                        repeat[1],
+                        space.unwrap_or_default() as u8,
+                        0,
+                    ]);
+                    keyframes.iter().for_each(|keyframe| {
+                        let _ = payload.extend_from_slice(keyframe);
+                    });
+                    let _ = payload.resize(payload.len().div_ceil(8) * 8, 0);
+                }
+                _ => {}
+            }
+            options.finish()?;
+            Ok((SerialCommand::AddState, payload))
+        }
+        "remove" => {
+            let slot = parse_nibble(next_arg(args, "slot")?, "slot")?;
+            Ok((
+                SerialCommand::RemoveState,
+                Vec::from_slice(&data_with(&[state << 7 | led_idx, slot << 4])).unwrap(),
+            ))
+        }
+        "clear" => Ok((
+            SerialCommand::ClearStates,
+            Vec::from_slice(&data_with(&[state << 7 | led_idx])).unwrap(),
+        )),
+        "flow" => {
+            let slot = parse_nibble(next_arg(args, "slot")?, "slot")?;
+            let mut options = Options::parse(args)?;
+            let repeat_count = options.take("repeat", |value| value.parse().ok())?;
+            let condition = options.take("if", parse_condition)?;
+            let branch = match condition {
+                Some(condition) => Some(Branch {
+                    condition,
+                    target: options
+                        .take("goto", |value| parse_nibble(value, "goto").ok())?
+                        .ok_or(TextError::MissingArgument("goto"))?
+                        as usize,
+                }),
+                None => None,
+            };
+            options.finish()?;
+            let flow = Flow {
+                repeat_count: repeat_count.unwrap_or(0),
+                branch,
+            };
+            let for_state =
+                ButtonState::try_from(state).map_err(|_| TextError::InvalidArgument("state"))?;
+            Ok((
+                SerialCommand::SetStateFlow,
+                Vec::from_slice(&flow.to_data(led_idx as usize, &for_state, slot as usize))
+                    .unwrap(),
+            ))
+        }
+        _ => Err(TextError::InvalidArgument("add|remove|clear|flow")),
+    }
+}
+
+fn next_arg<'a>(
+    args: &mut impl Iterator<Item = &'a str>,
+    name: &'static str,
+) -> Result<&'a str, TextError> {
+    args.next().ok_or(TextError::MissingArgument(name))
+}
+
+/// Decimal value fitting in 4 bits, e.g. led or slot index
+fn parse_nibble(value: &str, name: &'static str) -> Result<u8, TextError> {
+    match value.parse::<u8>() {
+        Ok(value) if value <= 0b00001111 => Ok(value),
+        _ => Err(TextError::InvalidArgument(name)),
+    }
+}
+
+/// Hex byte, e.g. brightness
+fn parse_hex(value: &str) -> Option<u8> {
+    u8::from_str_radix(value, 16).ok()
+}
+
+/// Decimal gamma with at most one digit after the point, e.g. `2.2`, encoded in tenths
+fn parse_gamma(value: &str) -> Result<u8, TextError> {
+    let (whole, tenths) = value.split_once('.').unwrap_or((value, "0"));
+    match (whole.parse::<u8>(), tenths.parse::<u8>()) {
+        (Ok(whole), Ok(digit)) if tenths.len() == 1 => whole
+            .checked_mul(10)
+            .and_then(|gamma| gamma.checked_add(digit))
+            .filter(|gamma| (1..=MAX_GAMMA).contains(gamma))
+            .ok_or(TextError::InvalidArgument("gamma")),
+        _ => Err(TextError::InvalidArgument("gamma")),
+    }
+}
+
+/// `<ticks>:<brightness hex>:<rrggbb>`, encoded as in `AddState`
+fn parse_keyframe(value: &str) -> Option<[u8; 6]> {
+    let mut fields = value.split(':');
+    let time: u16 = fields.next()?.parse().ok()?;
+    let brightness = parse_hex(fields.next()?)?;
+    let colour = parse_colour(fields.next()?)?;
+    if fields.next().is_some() {
+        return None;
+    }
+    let [high, low] = time.to_be_bytes();
+    Some([high, low, brightness, colour.red, colour.green, colour.blue])
+}
+
+/// `held:<ticks>`, `presses:<n>`, `flag:<n>` or `noflag:<n>`
+fn parse_condition(value: &str) -> Option<Condition> {
+    let (name, value) = value.split_once(':')?;
+    let flag = || value.parse().ok().and_then(Flag::new);
+    match name {
+        "held" => value.parse().ok().map(Condition::HeldFor),
+        "presses" => value.parse().ok().map(Condition::Presses),
+        "flag" => flag().map(Condition::FlagSet),
+        "noflag" => flag().map(Condition::FlagClear),
+        _ => None,
+    }
+}
+
+/// Names of all `name=value` options, see [`Options`]
+const OPTION_NAMES: [&str; 14] = [
+    "slot",
+    "next",
+    "ease",
+    "to",
+    "to_brightness",
+    "space",
+    "off",
+    "duty",
+    "repeat",
+    "speed",
+    "key",
+    "seed",
+    "if",
+    "goto",
+];
+
+/// Hues per second of a `hue_cycle` without the `speed` option, a full turn in 6 seconds
+const DEFAULT_HUE_SPEED: i16 = 256;
+
+/// Changes per second of a `flicker` without the `speed` option, about as fast as a candle
+const DEFAULT_FLICKER_SPEED: u8 = 12;
+
+/// `name=value` options following the arguments of a command. Each option is taken by its
+/// parser, options left over once the command is parsed are not valid for it.
+struct Options<'a> {
+    // Options other than `key` can be given once
+    options: Vec<(&'a str, &'a str), { OPTION_NAMES.len() + MAX_KEYFRAMES }>,
+}
+
+impl<'a> Options<'a> {
+    fn parse(args: impl Iterator<Item = &'a str>) -> Result<Self, TextError> {
+        let mut options = Vec::new();
+        for arg in args {
+            let option = arg
+                .split_once('=')
+                .ok_or(TextError::InvalidArgument("option"))?;
+            options
+                .push(option)
+                .map_err(|_| TextError::InvalidArgument("option"))?;
+        }
+        Ok(Options { options })
+    }
+
+    fn take<T>(
+        &mut self,
+        name: &'static str,
+        parse: impl FnOnce(&str) -> Option<T>,
+    ) -> Result<Option<T>, TextError> {
+        match self.options.iter().position(|(option, _)| *option == name) {
+            Some(idx) => {
+                // Keeps the order of repeated options
+                let (_, value) = self.options.remove(idx);
+                parse(value)
+                    .map(Some)
+                    .ok_or(TextError::InvalidArgument(name))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn finish(self) -> Result<(), TextError> {
+        match self.options.first() {
+            Some((option, _)) => Err(TextError::InvalidArgument(
+                OPTION_NAMES
+                    .into_iter()
+                    .find(|name| name == option)
+                    .unwrap_or("option"),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+fn parse_button_state(value: &str) -> Result<u8, TextError> {
+    button_state_from_name(value)
+        .map(|state| state as u8)
+        .ok_or(TextError::InvalidArgument("state"))
+}
+
+/// `idle` or `pressed`
+pub fn button_state_from_name(name: &str) -> Option<ButtonState> {
+    match name {
+        "idle" => Some(ButtonState::Idle),
+        "pressed" => Some(ButtonState::Pressed),
+        _ => None,
+    }
+}
+
+/// Hex `rrggbb`, optionally prefixed with `#`
+pub fn parse_colour(value: &str) -> Option<Colour> {
+    let value = value.strip_prefix('#').unwrap_or(value);
+    match (value.len(), u32::from_str_radix(value, 16)) {
+        (6, Ok(colour)) if value.bytes().all(|byte| byte.is_ascii_hexdigit()) => {
+            let [_, red, green, blue] = colour.to_be_bytes();
+            Some(Colour::rgb(red, green, blue))
+        }
+        _ => None,
+    }
+}
@@ -1,31 +1,1017 @@
-extern crate alloc;
-use crate::{rgbleds::LedState, serial_protocol::ParseError, Colour};
-use alloc::boxed::Box;
-
-/// Transition defines the state in a function of time
-// pub type Transition = fn(current_ticks: usize) -> TransitionResult;
-pub(crate) type TransitionFunction = Box<dyn Fn(usize) -> TransitionResult>;
-
-pub fn transition_function_try_from_bytes(
-    bytes: &[u8; 8],
-) -> Result<TransitionFunction, ParseError> {
-    let function = match (bytes[0] >> 4) & 0b0111 {
-        0 => solid,
-        1 => fade_out,
-        2 => fade_in,
-        _ => return Err(ParseError::InvalidData),
-    };
-
-    let next_state = bytes[1] & 0b00001111;
-    let brightness = bytes[2];
-    let colour = Colour::rgb(bytes[3], bytes[4], bytes[5]);
-    let duration_ticks = (bytes[6] as usize) << 8 | bytes[7] as usize;
-    Ok(function(
-        brightness,
-        colour,
-        duration_ticks,
-        next_state as usize,
-    ))
+use core::fmt;
+
+use crate::{
+    rgbleds::{level_from_brightness, LedState, MAX_LEVEL},
+    serial_protocol::ParseError,
+    ButtonState, Colour, HUE_RANGE,
+};
+use heapless::Vec;
+
+/// Longest `AddState` payload of a transition, see [`TransitionKind::payload_len`]
+pub const MAX_TRANSITION_PAYLOAD: usize = 8 + (MAX_KEYFRAMES * KEYFRAME_SIZE).div_ceil(8) * 8;
+
+pub const MAX_KEYFRAMES: usize = 8;
+
+/// Length of the `AddState` payload of a fade with a curve other than linear
+const EASED_FADE_PAYLOAD: usize = 16;
+
+/// Bytes of a single keyframe in the `AddState` payload: time, brightness and colour
+const KEYFRAME_SIZE: usize = 6;
+
+/// The speed of a hue cycle is the number of hues it moves by in this many ticks (a second)
+pub const HUE_SPEED_TICKS: usize = 1000;
+
+/// The speed of a flicker is the number of times it changes its level in this many ticks (a
+/// second)
+pub const FLICKER_SPEED_TICKS: usize = 1000;
+
+/// State of a led as a function of time, along with the parameters it was created from, so
+/// that it can be compared, reported back to the host or stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transition {
+    /// Constant colour, forever if `duration_ticks` is 0
+    Solid {
+        brightness: u8,
+        colour: Colour,
+        duration_ticks: usize,
+        next_state: TransitionIndex,
+    },
+    /// From `brightness` down to 0
+    FadeOut {
+        brightness: u8,
+        colour: Colour,
+        duration_ticks: usize,
+        next_state: TransitionIndex,
+        easing: Easing,
+    },
+    /// From 0 up to `brightness`
+    FadeIn {
+        brightness: u8,
+        colour: Colour,
+        duration_ticks: usize,
+        next_state: TransitionIndex,
+        easing: Easing,
+    },
+    /// From `from` at `brightness_from` to `to` at `brightness_to`
+    Crossfade {
+        from: Colour,
+        to: Colour,
+        brightness_from: u8,
+        brightness_to: u8,
+        duration_ticks: usize,
+        next_state: TransitionIndex,
+        easing: Easing,
+        space: ColourSpace,
+    },
+    /// `on` for `duty` percent of every period and `off` for the rest, finishes after
+    /// `repeat_count` periods or never if it is 0
+    Blink {
+        brightness: u8,
+        on: Colour,
+        off: Colour,
+        period_ticks: usize,
+        duty: u8,
+        repeat_count: usize,
+        next_state: TransitionIndex,
+    },
+    /// Around the colour wheel from `start_hue`, moving by `speed` hues every
+    /// [`HUE_SPEED_TICKS`], forever if `duration_ticks` is 0
+    HueCycle {
+        brightness: u8,
+        start_hue: u16,
+        speed: i16,
+        saturation: u8,
+        duration_ticks: usize,
+        next_state: TransitionIndex,
+    },
+    /// `colour` dimmed by random amounts of up to `intensity` (out of 255), blending into a new
+    /// random level `speed` times every [`FLICKER_SPEED_TICKS`]. The levels only depend on the
+    /// `seed`. Runs forever if `duration_ticks` is 0.
+    Flicker {
+        colour: Colour,
+        intensity: u8,
+        speed: u8,
+        seed: u32,
+        duration_ticks: usize,
+        next_state: TransitionIndex,
+    },
+    /// Through the `keyframes`, blending each one into the next, finishes after playing them
+    /// `repeat_count` times or never if it is 0
+    Keyframes {
+        keyframes: Keyframes,
+        repeat_count: usize,
+        next_state: TransitionIndex,
+        easing: Easing,
+        space: ColourSpace,
+    },
+}
+
+impl Transition {
+    pub fn render(&self, counter: usize) -> TransitionResult {
+        match *self {
+            Transition::Solid {
+                brightness,
+                colour,
+                duration_ticks,
+                next_state,
+            } => {
+                if duration_ticks == 0 || counter < duration_ticks {
+                    TransitionResult::InProgress(LedState::new(brightness, &colour))
+                } else {
+                    TransitionResult::Finished(next_state)
+                }
+            }
+            Transition::FadeOut {
+                brightness,
+                colour,
+                duration_ticks,
+                next_state,
+                easing,
+            } => {
+                if counter < duration_ticks {
+                    // The same curve as the fade in, played backwards
+                    TransitionResult::InProgress(LedState::with_level(
+                        easing.level(brightness, duration_ticks - counter, duration_ticks),
+                        &colour,
+                    ))
+                } else {
+                    TransitionResult::Finished(next_state)
+                }
+            }
+            Transition::FadeIn {
+                brightness,
+                colour,
+                duration_ticks,
+                next_state,
+                easing,
+            } => {
+                if counter < duration_ticks {
+                    TransitionResult::InProgress(LedState::with_level(
+                        easing.level(brightness, counter, duration_ticks),
+                        &colour,
+                    ))
+                } else {
+                    TransitionResult::Finished(next_state)
+                }
+            }
+            Transition::Crossfade {
+                from,
+                to,
+                brightness_from,
+                brightness_to,
+                duration_ticks,
+                next_state,
+                easing,
+                space,
+            } => {
+                if counter < duration_ticks {
+                    let progress = easing.progress(counter, duration_ticks);
+                    TransitionResult::InProgress(LedState::with_level(
+                        mix_level(
+                            level_from_brightness(brightness_from),
+                            level_from_brightness(brightness_to),
+                            progress,
+                        ),
+                        &space.mix(&from, &to, progress),
+                    ))
+                } else {
+                    TransitionResult::Finished(next_state)
+                }
+            }
+            Transition::Blink {
+                brightness,
+                on,
+                off,
+                period_ticks,
+                duty,
+                repeat_count,
+                next_state,
+            } => {
+                if period_ticks == 0
+                    || (repeat_count != 0 && counter / period_ticks >= repeat_count)
+                {
+                    TransitionResult::Finished(next_state)
+                } else {
+                    let on_ticks = (period_ticks as u64 * duty as u64 / 100) as usize;
+                    let colour = if counter % period_ticks < on_ticks {
+                        on
+                    } else {
+                        off
+                    };
+                    TransitionResult::InProgress(LedState::new(brightness, &colour))
+                }
+            }
+            Transition::HueCycle {
+                brightness,
+                start_hue,
+                speed,
+                saturation,
+                duration_ticks,
+                next_state,
+            } => {
+                if duration_ticks == 0 || counter < duration_ticks {
+                    let turned = speed as i64 * counter as i64 / HUE_SPEED_TICKS as i64;
+                    let hue = (start_hue as i64 + turned).rem_euclid(HUE_RANGE as i64);
+                    TransitionResult::InProgress(LedState::new(
+                        brightness,
+                        &Colour::hsv(hue as u16, saturation, 0xff),
+                    ))
+                } else {
+                    TransitionResult::Finished(next_state)
+                }
+            }
+            Transition::Flicker {
+                colour,
+                intensity,
+                speed,
+                seed,
+                duration_ticks,
+                next_state,
+            } => {
+                if duration_ticks == 0 || counter < duration_ticks {
+                    let step_ticks = (FLICKER_SPEED_TICKS / speed.max(1) as usize).max(1);
+                    let step = counter / step_ticks;
+                    let progress = ((counter % step_ticks) as u64 * EASING_ONE as u64
+                        / step_ticks as u64) as u32;
+                    let level = mix_level(
+                        flicker_level(seed, step, intensity),
+                        flicker_level(seed, step + 1, intensity),
+                        progress,
+                    );
+                    TransitionResult::InProgress(LedState::with_level(level, &colour))
+                } else {
+                    TransitionResult::Finished(next_state)
+                }
+            }
+            Transition::Keyframes {
+                keyframes,
+                repeat_count,
+                next_state,
+                easing,
+                space,
+            } => {
+                let period_ticks = keyframes.duration_ticks();
+                if period_ticks == 0
+                    || (repeat_count != 0 && counter / period_ticks >= repeat_count)
+                {
+                    TransitionResult::Finished(next_state)
+                } else {
+                    TransitionResult::InProgress(keyframes.render(
+                        counter % period_ticks,
+                        easing,
+                        space,
+                    ))
+                }
+            }
+        }
+    }
+
+    pub fn kind(&self) -> TransitionKind {
+        match self {
+            Transition::Solid { .. } => TransitionKind::Solid,
+            Transition::FadeOut { .. } => TransitionKind::FadeOut,
+            Transition::FadeIn { .. } => TransitionKind::FadeIn,
+            Transition::Crossfade { .. } => TransitionKind::Crossfade,
+            Transition::Blink { .. } => TransitionKind::Blink,
+            Transition::HueCycle { .. } => TransitionKind::HueCycle,
+            Transition::Flicker { .. } => TransitionKind::Flicker,
+            Transition::Keyframes { .. } => TransitionKind::Keyframes,
+        }
+    }
+
+    /// Use the given curve for a fade, a crossfade or between keyframes, other transitions are
+    /// left unchanged
+    pub fn with_easing(mut self, easing: Easing) -> Self {
+        if let Transition::Keyframes {
+            easing: current, ..
+        } = &mut self
+        {
+            *current = easing;
+        } else if let Transition::FadeOut {
+            easing: current, ..
+        }
+        | Transition::FadeIn {
+            easing: current, ..
+        }
+        | Transition::Crossfade {
+            easing: current, ..
+        } = &mut self
+        {
+            *current = easing;
+        }
+        self
+    }
+
+    /// Blend the colours of a crossfade or of keyframes in the given space, other transitions are
+    /// left unchanged
+    pub fn with_colour_space(mut self, space: ColourSpace) -> Self {
+        if let Transition::Crossfade { space: current, .. }
+        | Transition::Keyframes { space: current, .. } = &mut self
+        {
+            *current = space;
+        }
+        self
+    }
+
+    pub fn next_state(&self) -> TransitionIndex {
+        match *self {
+            Transition::Solid { next_state, .. }
+            | Transition::FadeOut { next_state, .. }
+            | Transition::FadeIn { next_state, .. }
+            | Transition::Crossfade { next_state, .. }
+            | Transition::Blink { next_state, .. }
+            | Transition::HueCycle { next_state, .. }
+            | Transition::Flicker { next_state, .. }
+            | Transition::Keyframes { next_state, .. } => next_state,
+        }
+    }
+
+    /// Ticks after which [`Transition::render`] finishes, `0` if it never does
+    pub fn duration_ticks(&self) -> usize {
+        match *self {
+            Transition::Solid { duration_ticks, .. }
+            | Transition::FadeOut { duration_ticks, .. }
+            | Transition::FadeIn { duration_ticks, .. }
+            | Transition::Crossfade { duration_ticks, .. }
+            | Transition::HueCycle { duration_ticks, .. }
+            | Transition::Flicker { duration_ticks, .. } => duration_ticks,
+            Transition::Blink {
+                period_ticks,
+                repeat_count,
+                ..
+            } => period_ticks.saturating_mul(repeat_count),
+            Transition::Keyframes {
+                keyframes,
+                repeat_count,
+                ..
+            } => keyframes.duration_ticks().saturating_mul(repeat_count),
+        }
+    }
+
+    /// Encode the transition as the payload of an `AddState` message, which is longer than a
+    /// single frame for some transitions
+    pub fn to_payload(
+        &self,
+        led_idx: usize,
+        for_state: &ButtonState,
+        state_idx: usize,
+    ) -> Vec<u8, MAX_TRANSITION_PAYLOAD> {
+        // Bytes 2-7, usually the brightness, the colour and the duration
+        let fields = match *self {
+            Transition::Solid {
+                brightness,
+                colour,
+                duration_ticks,
+                ..
+            } => common_fields(brightness, colour.to_bytes(), duration_ticks),
+            Transition::FadeOut {
+                brightness,
+                colour,
+                duration_ticks,
+                ..
+            }
+            | Transition::FadeIn {
+                brightness,
+                colour,
+                duration_ticks,
+                ..
+            } => common_fields(brightness, colour.to_bytes(), duration_ticks),
+            Transition::Crossfade {
+                from,
+                brightness_from,
+                duration_ticks,
+                ..
+            } => common_fields(brightness_from, from.to_bytes(), duration_ticks),
+            Transition::Blink {
+                brightness,
+                on,
+                period_ticks,
+                ..
+            } => common_fields(brightness, on.to_bytes(), period_ticks),
+            Transition::HueCycle {
+                brightness,
+                start_hue,
+                saturation,
+                duration_ticks,
+                ..
+            } => {
+                // There is no colour, the hue and saturation are sent in its place
+                let [high, low] = start_hue.to_be_bytes();
+                common_fields(brightness, [high, low, saturation], duration_ticks)
+            }
+            Transition::Flicker {
+                colour,
+                intensity,
+                duration_ticks,
+                ..
+            } => {
+                // Flickers are always at the full brightness, the intensity is sent in its place
+                common_fields(intensity, colour.to_bytes(), duration_ticks)
+            }
+            Transition::Keyframes {
+                keyframes,
+                repeat_count,
+                easing,
+                space,
+                ..
+            } => {
+                // The keyframes have their own brightness, the curve is sent in its place
+                let [high, low] = saturate_u16(repeat_count).to_be_bytes();
+                [
+                    easing.code(),
+                    keyframes.len() as u8,
+                    high,
+                    low,
+                    space as u8,
+                    0,
+                ]
+            }
+        };
+        let mut payload = Vec::new();
+        let _ = payload.extend_from_slice(&[
+            (*for_state as u8) << 7 | (self.kind() as u8) << 4 | (led_idx as u8 & 0b00001111),
+            (state_idx as u8) << 4 | (self.next_state() as u8 & 0b00001111),
+        ]);
+        let _ = payload.extend_from_slice(&fields);
+        match *self {
+            // Linear fades are sent as a single frame, as before the curves were added
+            Transition::FadeOut { easing, .. } | Transition::FadeIn { easing, .. }
+                if easing != Easing::Linear =>
+            {
+                let _ = payload.extend_from_slice(&[easing.code(), 0, 0, 0, 0, 0, 0, 0]);
+            }
+            Transition::Crossfade {
+                to,
+                brightness_to,
+                space,
+                easing,
+                ..
+            } => {
+                let _ = payload.extend_from_slice(&[
+                    brightness_to,
+                    to.red,
+                    to.green,
+                    to.blue,
+                    space as u8,
+                    easing.code(),
+                    0,
+                    0,
+                ]);
+            }
+            Transition::Blink {
+                off,
+                duty,
+                repeat_count,
+                ..
+            } => {
+                let [high, low] = saturate_u16(repeat_count).to_be_bytes();
+                let _ = payload
+                    .extend_from_slice(&[duty, off.red, off.green, off.blue, high, low, 0, 0]);
+            }
+            Transition::HueCycle { speed, .. } => {
+                let [high, low] = speed.to_be_bytes();
+                let _ = payload.extend_from_slice(&[high, low, 0, 0, 0, 0, 0, 0]);
+            }
+            Transition::Flicker { speed, seed, .. } => {
+                let [seed0, seed1, seed2, seed3] = seed.to_be_bytes();
+                let _ = payload.extend_from_slice(&[speed, seed0, seed1, seed2, seed3, 0, 0, 0]);
+            }
+            Transition::Keyframes { keyframes, .. } => {
+                for keyframe in keyframes.as_slice() {
+                    let _ = payload.extend_from_slice(&keyframe.to_bytes());
+                }
+                // Padded to whole frames
+                let _ = payload.resize(payload.len().div_ceil(8) * 8, 0);
+            }
+            _ => {}
+        }
+        payload
+    }
+}
+
+/// Bytes 2-7 of an `AddState` payload
+fn common_fields(brightness: u8, colour: [u8; 3], duration_ticks: usize) -> [u8; 6] {
+    let [high, low] = saturate_u16(duration_ticks).to_be_bytes();
+    [brightness, colour[0], colour[1], colour[2], high, low]
+}
+
+/// Durations and counts longer than fit in the payload are sent as the longest one
+fn saturate_u16(value: usize) -> u16 {
+    value.min(u16::MAX as usize) as u16
+}
+
+/// Decode the payload of an `AddState` message
+impl TryFrom<&[u8]> for Transition {
+    type Error = ParseError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let kind = TransitionKind::from_first_byte(*bytes.first().ok_or(ParseError::InvalidData)?)?;
+        let eased_fade = matches!(kind, TransitionKind::FadeOut | TransitionKind::FadeIn)
+            && bytes.len() == EASED_FADE_PAYLOAD;
+        if bytes.len() != kind.payload_len(bytes) && !eased_fade {
+            return Err(ParseError::InvalidMessageLength);
+        }
+
+        let next_state = (bytes[1] & 0b00001111) as usize;
+        let brightness = bytes[2];
+        let colour = Colour::rgb(bytes[3], bytes[4], bytes[5]);
+        let duration_ticks = (bytes[6] as usize) << 8 | bytes[7] as usize;
+        // The upper bits of the brightness are ignored by the leds
+        let (transition, easing_code) = match kind {
+            TransitionKind::Solid => {
+                return Ok(solid(brightness, colour, duration_ticks, next_state))
+            }
+            TransitionKind::Blink => {
+                let duty = bytes[8];
+                if duty > 100 {
+                    return Err(ParseError::InvalidTransition);
+                }
+                return Ok(blink(
+                    brightness,
+                    colour,
+                    Colour::rgb(bytes[9], bytes[10], bytes[11]),
+                    duration_ticks,
+                    duty,
+                    (bytes[12] as usize) << 8 | bytes[13] as usize,
+                    next_state,
+                ));
+            }
+            TransitionKind::HueCycle => {
+                let start_hue = u16::from_be_bytes([bytes[3], bytes[4]]);
+                if start_hue >= HUE_RANGE {
+                    return Err(ParseError::InvalidTransition);
+                }
+                return Ok(hue_cycle(
+                    start_hue,
+                    i16::from_be_bytes([bytes[8], bytes[9]]),
+                    bytes[5],
+                    brightness,
+                    duration_ticks,
+                    next_state,
+                ));
+            }
+            TransitionKind::Flicker => {
+                let speed = bytes[8];
+                if speed == 0 {
+                    return Err(ParseError::InvalidTransition);
+                }
+                return Ok(flicker(
+                    colour,
+                    brightness,
+                    speed,
+                    u32::from_be_bytes([bytes[9], bytes[10], bytes[11], bytes[12]]),
+                    duration_ticks,
+                    next_state,
+                ));
+            }
+            TransitionKind::Keyframes => {
+                let points = bytes[8..]
+                    .chunks_exact(KEYFRAME_SIZE)
+                    .take(bytes[3] as usize)
+                    .map(Keyframe::from_bytes);
+                let transition = keyframes(
+                    Keyframes::new(points).ok_or(ParseError::InvalidTransition)?,
+                    (bytes[4] as usize) << 8 | bytes[5] as usize,
+                    next_state,
+                )
+                .with_colour_space(
+                    ColourSpace::try_from(bytes[6]).map_err(|_| ParseError::InvalidTransition)?,
+                );
+                (transition, bytes[2])
+            }
+            // The curve of a fade is in its optional second frame
+            TransitionKind::FadeOut => (
+                fade_out(brightness, colour, duration_ticks, next_state),
+                bytes.get(8).copied().unwrap_or(0),
+            ),
+            TransitionKind::FadeIn => (
+                fade_in(brightness, colour, duration_ticks, next_state),
+                bytes.get(8).copied().unwrap_or(0),
+            ),
+            TransitionKind::Crossfade => {
+                let transition = crossfade(
+                    colour,
+                    Colour::rgb(bytes[9], bytes[10], bytes[11]),
+                    brightness,
+                    bytes[8],
+                    duration_ticks,
+                    next_state,
+                )
+                .with_colour_space(
+                    ColourSpace::try_from(bytes[12]).map_err(|_| ParseError::InvalidTransition)?,
+                );
+                (transition, bytes[13])
+            }
+        };
+        Easing::from_code(easing_code)
+            .map(|easing| transition.with_easing(easing))
+            .ok_or(ParseError::InvalidTransition)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum TransitionKind {
+    Solid = 0x0,
+    FadeOut = 0x1,
+    FadeIn = 0x2,
+    Crossfade = 0x3,
+    Blink = 0x4,
+    HueCycle = 0x5,
+    Keyframes = 0x6,
+    Flicker = 0x7,
+}
+
+/// Transitions that can be sent in `AddState`, reported in `DeviceInfo`
+pub const SUPPORTED_TRANSITIONS: [TransitionKind; 8] = [
+    TransitionKind::Solid,
+    TransitionKind::FadeOut,
+    TransitionKind::FadeIn,
+    TransitionKind::Crossfade,
+    TransitionKind::Blink,
+    TransitionKind::HueCycle,
+    TransitionKind::Keyframes,
+    TransitionKind::Flicker,
+];
+
+impl TryFrom<u8> for TransitionKind {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(TransitionKind::Solid),
+            1 => Ok(TransitionKind::FadeOut),
+            2 => Ok(TransitionKind::FadeIn),
+            3 => Ok(TransitionKind::Crossfade),
+            4 => Ok(TransitionKind::Blink),
+            5 => Ok(TransitionKind::HueCycle),
+            6 => Ok(TransitionKind::Keyframes),
+            7 => Ok(TransitionKind::Flicker),
+            _ => Err(value),
+        }
+    }
+}
+
+impl TransitionKind {
+    /// Name used in the text mode
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransitionKind::Solid => "solid",
+            TransitionKind::FadeOut => "fade_out",
+            TransitionKind::FadeIn => "fade_in",
+            TransitionKind::Crossfade => "crossfade",
+            TransitionKind::Blink => "blink",
+            TransitionKind::HueCycle => "hue_cycle",
+            TransitionKind::Keyframes => "keyframes",
+            TransitionKind::Flicker => "flicker",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        SUPPORTED_TRANSITIONS
+            .into_iter()
+            .find(|kind| kind.name() == name)
+    }
+
+    /// Kind sent in bits 4-6 of the first byte of an `AddState` payload
+    pub fn from_first_byte(byte: u8) -> Result<Self, ParseError> {
+        TransitionKind::try_from((byte >> 4) & 0b0111).map_err(|_| ParseError::InvalidTransition)
+    }
+
+    /// Length of the `AddState` payload starting with `header` (at least its first frame), a
+    /// multiple of the 8 data bytes of a frame. Fades may be followed by a second frame with
+    /// their curve.
+    pub fn payload_len(&self, header: &[u8]) -> usize {
+        match self {
+            TransitionKind::Solid | TransitionKind::FadeOut | TransitionKind::FadeIn => 8,
+            TransitionKind::Crossfade
+            | TransitionKind::Blink
+            | TransitionKind::HueCycle
+            | TransitionKind::Flicker => 16,
+            TransitionKind::Keyframes => {
+                let count = header.get(3).map_or(0, |count| *count as usize);
+                8 + (count * KEYFRAME_SIZE).div_ceil(8) * 8
+            }
+        }
+    }
+}
+
+/// Brightness and colour of a led at `time_ticks` from the start of [`Transition::Keyframes`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keyframe {
+    pub time_ticks: u16,
+    pub brightness: u8,
+    pub colour: Colour,
+}
+
+impl Keyframe {
+    pub fn new(time_ticks: u16, brightness: u8, colour: Colour) -> Self {
+        Self {
+            time_ticks,
+            brightness,
+            colour,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; KEYFRAME_SIZE] {
+        let [high, low] = self.time_ticks.to_be_bytes();
+        let [red, green, blue] = self.colour.to_bytes();
+        [high, low, self.brightness, red, green, blue]
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Keyframe::new(
+            u16::from_be_bytes([bytes[0], bytes[1]]),
+            bytes[2],
+            Colour::rgb(bytes[3], bytes[4], bytes[5]),
+        )
+    }
+}
+
+/// 2 to [`MAX_KEYFRAMES`] keyframes in the order of their times, the last one at a time after
+/// the start. Keyframes at the same time switch between them immediately.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Keyframes {
+    // Unused keyframes are left black, so that the comparison only depends on the used ones
+    keyframes: [Keyframe; MAX_KEYFRAMES],
+    len: usize,
+}
+
+impl Keyframes {
+    /// `None` if there are too few or too many keyframes, or if they are not in order
+    pub fn from_slice(keyframes: &[Keyframe]) -> Option<Self> {
+        Self::new(keyframes.iter().copied())
+    }
+
+    fn new(keyframes: impl Iterator<Item = Keyframe>) -> Option<Self> {
+        let mut result = Keyframes {
+            keyframes: [Keyframe::new(0, 0, Colour::rgb(0, 0, 0)); MAX_KEYFRAMES],
+            len: 0,
+        };
+        for keyframe in keyframes {
+            let slot = result.keyframes.get_mut(result.len)?;
+            *slot = keyframe;
+            result.len += 1;
+        }
+        let keyframes = result.as_slice();
+        let in_order = keyframes
+            .windows(2)
+            .all(|pair| pair[0].time_ticks <= pair[1].time_ticks);
+        (keyframes.len() >= 2 && in_order && result.duration_ticks() > 0).then_some(result)
+    }
+
+    pub fn as_slice(&self) -> &[Keyframe] {
+        &self.keyframes[..self.len]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Time of the last keyframe, after which the keyframes start over
+    pub fn duration_ticks(&self) -> usize {
+        self.as_slice()
+            .last()
+            .map_or(0, |keyframe| keyframe.time_ticks as usize)
+    }
+
+    /// State at `position` ticks from the start, the first keyframe is held until its time
+    fn render(&self, position: usize, easing: Easing, space: ColourSpace) -> LedState {
+        let keyframes = self.as_slice();
+        let next = keyframes
+            .iter()
+            .position(|keyframe| keyframe.time_ticks as usize > position)
+            .unwrap_or(keyframes.len() - 1);
+        let to = &keyframes[next];
+        let from = &keyframes[next.saturating_sub(1)];
+        let from_time = from.time_ticks as usize;
+        let progress = match to.time_ticks as usize - from_time {
+            0 => EASING_ONE,
+            span => easing.progress(position.saturating_sub(from_time), span),
+        };
+        LedState::with_level(
+            mix_level(
+                level_from_brightness(from.brightness),
+                level_from_brightness(to.brightness),
+                progress,
+            ),
+            &space.mix(&from.colour, &to.colour, progress),
+        )
+    }
+}
+
+impl fmt::Debug for Keyframes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.as_slice()).finish()
+    }
+}
+
+/// Space in which the colours of a crossfade are blended, sent in the flags byte of `AddState`
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[repr(u8)]
+pub enum ColourSpace {
+    /// Straight line between the colours, may pass through greyish colours
+    #[default]
+    Rgb = 0x0,
+    /// Around the shorter side of the colour wheel, keeps the colours saturated
+    Hsv = 0x1,
+}
+
+impl TryFrom<u8> for ColourSpace {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ColourSpace::Rgb),
+            1 => Ok(ColourSpace::Hsv),
+            _ => Err(value),
+        }
+    }
+}
+
+impl ColourSpace {
+    /// Name used in the text mode
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColourSpace::Rgb => "rgb",
+            ColourSpace::Hsv => "hsv",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [ColourSpace::Rgb, ColourSpace::Hsv]
+            .into_iter()
+            .find(|space| space.name() == name)
+    }
+
+    /// Colour at `progress` (a fraction of `EASING_ONE`) of the way from `from` to `to`
+    fn mix(&self, from: &Colour, to: &Colour, progress: u32) -> Colour {
+        match self {
+            ColourSpace::Rgb => Colour::rgb(
+                mix(from.red, to.red, progress),
+                mix(from.green, to.green, progress),
+                mix(from.blue, to.blue, progress),
+            ),
+            ColourSpace::Hsv => {
+                let (mut from_hue, from_saturation, from_value) = from.to_hsv();
+                let (mut to_hue, to_saturation, to_value) = to.to_hsv();
+                // Greys have no hue, keep the hue of the other colour instead of spinning
+                if from_saturation == 0 {
+                    from_hue = to_hue;
+                } else if to_saturation == 0 {
+                    to_hue = from_hue;
+                }
+                let range = HUE_RANGE as i32;
+                let mut distance = (to_hue as i32 - from_hue as i32).rem_euclid(range);
+                if distance > range / 2 {
+                    distance -= range;
+                }
+                let hue = from_hue as i32 + distance * progress as i32 / EASING_ONE as i32;
+                Colour::hsv(
+                    hue.rem_euclid(range) as u16,
+                    mix(from_saturation, to_saturation, progress),
+                    mix(from_value, to_value, progress),
+                )
+            }
+        }
+    }
+}
+
+/// Value at `progress` (a fraction of `EASING_ONE`) of the way from `from` to `to`
+fn mix(from: u8, to: u8, progress: u32) -> u8 {
+    let progress = progress.min(EASING_ONE) as i32;
+    (from as i32 + (to as i32 - from as i32) * progress / EASING_ONE as i32) as u8
+}
+
+/// [`mix`] of two levels of [`LedState`]
+fn mix_level(from: u16, to: u16, progress: u32) -> u16 {
+    let progress = progress.min(EASING_ONE) as i64;
+    (from as i64 + (to as i64 - from as i64) * progress / EASING_ONE as i64) as u16
+}
+
+/// Curve of a fade, a crossfade or between keyframes. A fade sends it in a second frame chained to
+/// its `AddState` frame, the other transitions in a byte of their own payload
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Easing {
+    #[default]
+    Linear,
+    Quadratic,
+    Cubic,
+    Sine,
+    Exponential,
+    /// Cubic, slow at both ends
+    EaseInOut,
+}
+
+pub const SUPPORTED_EASINGS: [Easing; 6] = [
+    Easing::Linear,
+    Easing::Quadratic,
+    Easing::Cubic,
+    Easing::Sine,
+    Easing::Exponential,
+    Easing::EaseInOut,
+];
+
+/// Fixed point 1.0 of the easing curves
+const EASING_ONE: u32 = 1 << 12;
+
+/// `1 - cos(x * pi / 2)` sampled at every 1/16
+const SINE_CURVE: [u16; 17] = [
+    0, 20, 79, 176, 312, 484, 690, 930, 1200, 1498, 1820, 2165, 2529, 2907, 3297, 3695, 4096,
+];
+
+/// `2^(10 * (x - 1))` sampled at every 1/16, starting from 0
+const EXPONENTIAL_CURVE: [u16; 17] = [
+    0, 6, 10, 15, 23, 35, 54, 83, 128, 197, 304, 470, 724, 1117, 1722, 2656, 4096,
+];
+
+impl Easing {
+    /// Byte sent in `AddState`, linear is 0 as for a fade sent without the chained frame
+    pub fn code(&self) -> u8 {
+        match self {
+            Easing::Linear => 0,
+            Easing::Quadratic => 1,
+            Easing::Cubic => 2,
+            Easing::Sine => 3,
+            Easing::Exponential => 4,
+            Easing::EaseInOut => 5,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        SUPPORTED_EASINGS
+            .into_iter()
+            .find(|easing| easing.code() == code)
+    }
+
+    /// Name used in the text mode
+    pub fn name(&self) -> &'static str {
+        match self {
+            Easing::Linear => "linear",
+            Easing::Quadratic => "quadratic",
+            Easing::Cubic => "cubic",
+            Easing::Sine => "sine",
+            Easing::Exponential => "exponential",
+            Easing::EaseInOut => "ease_in_out",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        SUPPORTED_EASINGS
+            .into_iter()
+            .find(|easing| easing.name() == name)
+    }
+
+    /// Level of [`LedState`] reached after `elapsed` of `duration` ticks of a fade in to
+    /// `brightness` (5 bits). The level has a much finer resolution than the brightness, so
+    /// slow fades do not step.
+    fn level(&self, brightness: u8, elapsed: usize, duration: usize) -> u16 {
+        mix_level(
+            0,
+            level_from_brightness(brightness),
+            self.progress(elapsed, duration),
+        )
+    }
+
+    /// Eased fraction (of `EASING_ONE`) of a transition after `elapsed` of `duration` ticks
+    fn progress(&self, elapsed: usize, duration: usize) -> u32 {
+        self.apply((elapsed as u64 * EASING_ONE as u64 / duration as u64) as u32)
+    }
+
+    /// `progress` and the result are fractions of `EASING_ONE`
+    fn apply(&self, progress: u32) -> u32 {
+        let x = progress.min(EASING_ONE);
+        match self {
+            Easing::Linear => x,
+            Easing::Quadratic => x * x / EASING_ONE,
+            Easing::Cubic => x * x / EASING_ONE * x / EASING_ONE,
+            Easing::Sine => sample(&SINE_CURVE, x),
+            Easing::Exponential => sample(&EXPONENTIAL_CURVE, x),
+            Easing::EaseInOut => {
+                if x < EASING_ONE / 2 {
+                    4 * x * x / EASING_ONE * x / EASING_ONE
+                } else {
+                    let y = 2 * (EASING_ONE - x);
+                    EASING_ONE - y * y / EASING_ONE * y / EASING_ONE / 2
+                }
+            }
+        }
+    }
+}
+
+/// Linear interpolation between the samples of a curve
+fn sample(curve: &[u16; 17], x: u32) -> u32 {
+    let segment = EASING_ONE / 16;
+    let idx = (x / segment) as usize;
+    if idx >= 16 {
+        return curve[16] as u32;
+    }
+    let (from, to) = (curve[idx] as u32, curve[idx + 1] as u32);
+    from + (to - from) * (x % segment) / segment
 }
 
 pub fn solid(
@@ -33,17 +1019,12 @@ pub fn solid(
     colour: Colour,
     duration_ticks: usize,
     transition_index: TransitionIndex,
-) -> TransitionFunction {
-    if duration_ticks != 0 {
-        Box::new(move |counter: usize| {
-            if counter < duration_ticks {
-                TransitionResult::InProgress(LedState::new(brightness, &colour))
-            } else {
-                TransitionResult::Finished(transition_index)
-            }
-        })
-    } else {
-        Box::new(move |_: usize| TransitionResult::InProgress(LedState::new(brightness, &colour)))
+) -> Transition {
+    Transition::Solid {
+        brightness,
+        colour,
+        duration_ticks,
+        next_state: transition_index,
     }
 }
 
@@ -52,18 +1033,14 @@ pub fn fade_out(
     colour: Colour,
     duration_ticks: usize,
     transition_index: TransitionIndex,
-) -> TransitionFunction {
-    Box::new(move |counter: usize| {
-        if counter < duration_ticks {
-            TransitionResult::InProgress(LedState::new(
-                (initial_brightness & 0b00011111)
-                    - (counter * (initial_brightness & 0b00011111) as usize / duration_ticks) as u8,
-                &colour,
-            ))
-        } else {
-            TransitionResult::Finished(transition_index)
-        }
-    })
+) -> Transition {
+    Transition::FadeOut {
+        brightness: initial_brightness,
+        colour,
+        duration_ticks,
+        next_state: transition_index,
+        easing: Easing::Linear,
+    }
 }
 
 pub fn fade_in(
@@ -71,17 +1048,131 @@ pub fn fade_in(
     colour: Colour,
     duration_ticks: usize,
     transition_index: TransitionIndex,
-) -> TransitionFunction {
-    Box::new(move |counter: usize| {
-        if counter < duration_ticks {
-            TransitionResult::InProgress(LedState::new(
-                (counter * (target_brightness & 0b00011111) as usize / duration_ticks) as u8,
-                &colour,
-            ))
-        } else {
-            TransitionResult::Finished(transition_index)
-        }
-    })
+) -> Transition {
+    Transition::FadeIn {
+        brightness: target_brightness,
+        colour,
+        duration_ticks,
+        next_state: transition_index,
+        easing: Easing::Linear,
+    }
+}
+
+/// Crossfade blending in RGB, see [`Transition::with_colour_space`] and
+/// [`Transition::with_easing`]
+pub fn crossfade(
+    from: Colour,
+    to: Colour,
+    brightness_from: u8,
+    brightness_to: u8,
+    duration_ticks: usize,
+    transition_index: TransitionIndex,
+) -> Transition {
+    Transition::Crossfade {
+        from,
+        to,
+        brightness_from,
+        brightness_to,
+        duration_ticks,
+        next_state: transition_index,
+        easing: Easing::Linear,
+        space: ColourSpace::Rgb,
+    }
+}
+
+/// Blink between `on` and `off`, `duty` (0 to 100) percent of every period on. Runs for
+/// `repeat_count` periods, or forever if it is 0.
+pub fn blink(
+    brightness: u8,
+    on: Colour,
+    off: Colour,
+    period_ticks: usize,
+    duty: u8,
+    repeat_count: usize,
+    transition_index: TransitionIndex,
+) -> Transition {
+    Transition::Blink {
+        brightness,
+        on,
+        off,
+        period_ticks,
+        duty: duty.min(100),
+        repeat_count,
+        next_state: transition_index,
+    }
+}
+
+/// Fully bright colours around the colour wheel, see [`HUE_RANGE`]. A negative `speed` goes
+/// backwards, from red to magenta.
+pub fn hue_cycle(
+    start_hue: u16,
+    speed: i16,
+    saturation: u8,
+    brightness: u8,
+    duration_ticks: usize,
+    transition_index: TransitionIndex,
+) -> Transition {
+    Transition::HueCycle {
+        brightness,
+        start_hue: start_hue % HUE_RANGE,
+        speed,
+        saturation,
+        duration_ticks,
+        next_state: transition_index,
+    }
+}
+
+/// `colour` flickering like a candle or a fire. The same `seed` always gives the same levels, so
+/// that the host can preview the flicker by rendering it the same way.
+pub fn flicker(
+    colour: Colour,
+    intensity: u8,
+    speed: u8,
+    seed: u32,
+    duration_ticks: usize,
+    transition_index: TransitionIndex,
+) -> Transition {
+    Transition::Flicker {
+        colour,
+        intensity,
+        speed: speed.max(1),
+        seed,
+        duration_ticks,
+        next_state: transition_index,
+    }
+}
+
+/// Level of [`LedState`] of a flicker at the start of a `step`, a hash of the seed and the step
+/// so that it does not depend on the previous steps nor on the platform. Small dips are more
+/// likely than deep ones.
+fn flicker_level(seed: u32, step: usize, intensity: u8) -> u16 {
+    let random = (splitmix64((seed as u64) << 32 | step as u32 as u64) >> 48) as u32;
+    let dip = random * random / MAX_LEVEL as u32 * intensity as u32 / 255;
+    (MAX_LEVEL as u32 - dip) as u16
+}
+
+/// Output function of the SplitMix64 generator, mixes all bits of `value` into every bit
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Keyframes blended linearly in RGB, see [`Transition::with_colour_space`] and
+/// [`Transition::with_easing`]. Plays them `repeat_count` times, or forever if it is 0.
+pub fn keyframes(
+    keyframes: Keyframes,
+    repeat_count: usize,
+    transition_index: TransitionIndex,
+) -> Transition {
+    Transition::Keyframes {
+        keyframes,
+        repeat_count,
+        next_state: transition_index,
+        easing: Easing::Linear,
+        space: ColourSpace::Rgb,
+    }
 }
 
 pub type TransitionIndex = usize;
@@ -1,22 +1,17 @@
-use core::cell::RefCell;
 use core::panic;
 use core::sync::atomic::{AtomicBool, Ordering};
 extern crate alloc;
 
-use crate::board::Board;
-use crate::serial_protocol::{NackType, ParseError, SerialCommand, SerialMessage};
-use crate::transitions::{solid, transition_function_try_from_bytes};
-use crate::{ButtonState, Colour};
-use core::todo;
+use crate::serial_connection::{
+    apply_connection_defaults, forward_button_events, serial_loop, ResetRequested, SerialTransport,
+    SharedBoard,
+};
 use defmt::*;
 use embassy_futures::join::join4;
 use embassy_rp::i2c;
 use embassy_rp::i2c::I2c;
-use embassy_rp::peripherals::{I2C0, SPI0, USB};
-use embassy_rp::spi::{self, Spi};
+use embassy_rp::peripherals::{I2C0, USB};
 use embassy_rp::usb::{Driver, Instance};
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::mutex::Mutex;
 use embassy_usb::class::cdc_acm::CdcAcmClass;
 use embassy_usb::class::hid::{HidReaderWriter, ReportId, RequestHandler};
 use embassy_usb::control::OutResponse;
@@ -27,10 +22,7 @@ use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
 
 use {defmt_rtt as _, panic_probe as _};
 
-type MutexedBoard = Mutex<
-    ThreadModeRawMutex,
-    RefCell<Board<I2c<'static, I2C0, i2c::Async>, Spi<'static, SPI0, spi::Async>>>,
->;
+type MutexedBoard = SharedBoard<I2c<'static, I2C0, i2c::Async>>;
 
 pub async fn setup_usb_device(driver: Driver<'static, USB>, board: &MutexedBoard) {
     // Create embassy-usb Config
@@ -98,7 +90,12 @@ pub async fn setup_usb_device(driver: Driver<'static, USB>, board: &MutexedBoard
 
     let in_fut = async {
         loop {
-            let key_states = { board.lock().await.get_mut().update_status().await.unwrap() };
+            let key_states = {
+                let mut _board = board.lock().await;
+                let key_states = _board.get_mut().update_status().await.unwrap();
+                forward_button_events(_board.get_mut());
+                key_states
+            };
             let mut keycodes = [0u8; 6];
 
             key_states
@@ -130,20 +127,11 @@ pub async fn setup_usb_device(driver: Driver<'static, USB>, board: &MutexedBoard
         loop {
             serial_class.wait_connection().await;
             info!("Serial connected!");
-            {
-                let mut _board = board.lock().await;
-                _board.get_mut().unlock_led_states();
-                _board.get_mut().enable_keyboard_input();
-                (0..16).for_each(|led| {
-                    _board.get_mut().add_led_state(
-                        led,
-                        0,
-                        solid(0x00, Colour::white(), 0, 0),
-                        &ButtonState::Idle,
-                    );
-                });
+            apply_connection_defaults(board.lock().await.get_mut());
+            if let Ok(ResetRequested) = serial_loop(&mut serial_class, board).await {
+                info!("Resetting the device");
+                cortex_m::peripheral::SCB::sys_reset()
             }
-            let _ = serial_loop(&mut serial_class, board).await;
             info!("Serial disconnected!");
         }
     };
@@ -236,7 +224,7 @@ impl Handler for DeviceHandler {
     }
 }
 
-struct Disconnected {}
+pub struct Disconnected {}
 
 impl From<EndpointError> for Disconnected {
     fn from(val: EndpointError) -> Self {
@@ -247,153 +235,14 @@ impl From<EndpointError> for Disconnected {
     }
 }
 
-async fn serial_loop<'d, T: Instance + 'd>(
-    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
-    board: &MutexedBoard,
-) -> Result<(), Disconnected> {
-    let mut buf = [0; 10];
-    loop {
-        let n = class.read_packet(&mut buf).await?;
-        debug!("Received {} bytes: {:x}", n, buf[0..n]);
-        if n == 10 {
-            match TryInto::<SerialMessage>::try_into(buf.as_slice()) {
-                Ok(sm) => {
-                    info!("Received message: {}", sm);
-                    match sm.get_command() {
-                        SerialCommand::EndOfStream | SerialCommand::ToBeContinued => {
-                            send_message(
-                                class,
-                                SerialMessage::nack_to_message(&sm, NackType::InvalidCommand),
-                            )
-                            .await?;
-                        }
-                        SerialCommand::SyncRequest => todo!(),
-                        SerialCommand::DeviceReset => {
-                            send_message(class, SerialMessage::ack_to(&sm)).await?;
-                            info!("Resetting the device");
-                            cortex_m::peripheral::SCB::sys_reset()
-                        }
-                        SerialCommand::DisableKeyboardInput => {
-                            board.lock().await.get_mut().disable_keyboard_input();
-                            send_message(class, SerialMessage::ack_to(&sm)).await?;
-                        }
-                        SerialCommand::EnableKeyboardInput => {
-                            board.lock().await.get_mut().enable_keyboard_input();
-                            send_message(class, SerialMessage::ack_to(&sm)).await?;
-                        }
-                        SerialCommand::LockButtonState => {
-                            let data = sm.get_data();
-                            let led_idx = 0b00001111 & data[0];
-                            let to_state = ButtonState::try_from(data[0] >> 7).unwrap();
-                            board
-                                .lock()
-                                .await
-                                .get_mut()
-                                .lock_led_state(led_idx as usize, &to_state);
-                            send_message(class, SerialMessage::ack_to(&sm)).await?;
-                        }
-                        SerialCommand::LockAllButtonStates => {
-                            let data = sm.get_data();
-                            let to_state = ButtonState::try_from(data[0] >> 7).unwrap();
-                            board.lock().await.get_mut().lock_led_states(&to_state);
-                            send_message(class, SerialMessage::ack_to(&sm)).await?;
-                        }
-                        SerialCommand::UnlockButtonState => {
-                            let data = sm.get_data();
-                            let led_idx = 0b00001111 & data[0];
-
-                            board
-                                .lock()
-                                .await
-                                .get_mut()
-                                .unlock_led_state(led_idx as usize);
-                            send_message(class, SerialMessage::ack_to(&sm)).await?;
-                        }
-                        SerialCommand::UnlockAllButtonStates => {
-                            board.lock().await.get_mut().unlock_led_states();
-                            send_message(class, SerialMessage::ack_to(&sm)).await?;
-                        }
-                        SerialCommand::AddState => {
-                            let data = sm.get_data();
-                            let led_idx = 0b00001111 & data[0];
-                            let transition_function = match transition_function_try_from_bytes(data)
-                            {
-                                Ok(f) => f,
-                                Err(e) => {
-                                    send_message(class, SerialMessage::nack_from_error(e)).await?;
-                                    continue;
-                                }
-                            };
-                            let for_state = ButtonState::try_from(data[0] >> 7).unwrap();
-                            let state_idx = data[1] >> 4;
-                            info!(
-                                "Added state for led {}: for_state: {}, state index: {}",
-                                led_idx, for_state, state_idx
-                            );
-                            board.lock().await.get_mut().add_led_state(
-                                led_idx as usize,
-                                state_idx as usize,
-                                transition_function,
-                                &for_state,
-                            );
-                            send_message(class, SerialMessage::ack_to(&sm)).await?;
-                        }
-                        SerialCommand::RemoveState => {
-                            let data = sm.get_data();
-                            let led_idx = 0b00001111 & data[0];
-                            let state_idx = data[1] >> 4;
-                            let for_state = ButtonState::try_from(data[0] >> 7).unwrap();
-                            board.lock().await.get_mut().remove_led_state(
-                                led_idx as usize,
-                                state_idx as usize,
-                                &for_state,
-                            );
-                            send_message(class, SerialMessage::ack_to(&sm)).await?;
-                        }
-                        SerialCommand::ClearStates => {
-                            let data = sm.get_data();
-                            let led_idx = 0b00001111 & data[0];
-                            let for_state = ButtonState::try_from(data[0] >> 7).unwrap();
-                            board
-                                .lock()
-                                .await
-                                .get_mut()
-                                .clear_led_queue(led_idx as usize, &[&for_state]);
-                            send_message(class, SerialMessage::ack_to(&sm)).await?;
-                        }
-                        SerialCommand::NackGeneral => todo!(),
-                        SerialCommand::NackInvalidCommand => todo!(),
-                        SerialCommand::NackParseError => todo!(),
-                        SerialCommand::NackDeviceError => todo!(),
-                        SerialCommand::NackDeviceBusy => todo!(),
-                        SerialCommand::Reserved => todo!(),
-                        SerialCommand::Ping => {
-                            send_message(class, SerialMessage::ack_to(&sm)).await?;
-                        }
-                        SerialCommand::Ack => todo!(),
-                    }
-                }
-                Err(err) => {
-                    error!("Failed to parse serial message: {}", err);
-                    send_message(class, SerialMessage::nack_from_error(err)).await?
-                }
-            }
-        } else {
-            error!("Failed to parse serial message - invalid length {}", n);
-            send_message(
-                class,
-                SerialMessage::nack_from_error(ParseError::InvalidMessageLength),
-            )
-            .await?
-        }
+impl<'d, T: Instance + 'd> SerialTransport for CdcAcmClass<'d, Driver<'d, T>> {
+    type Error = Disconnected;
+
+    async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, Disconnected> {
+        Ok(CdcAcmClass::read_packet(self, data).await?)
     }
-}
 
-async fn send_message<'d, T: Instance + 'd>(
-    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
-    message: SerialMessage,
-) -> Result<(), EndpointError> {
-    let bytes = message.to_bytes();
-    info!("Sending message: {}", message);
-    class.write_packet(&bytes).await
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), Disconnected> {
+        Ok(CdcAcmClass::write_packet(self, data).await?)
+    }
 }
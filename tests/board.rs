@@ -0,0 +1,134 @@
+use core::cell::RefCell;
+
+use embassy_sync::mutex::Mutex;
+use pico_soundboard::{
+    board::{Board, ButtonEvent},
+    calibration::Calibration,
+    mock::{block_on, MockI2c, MockLeds, MockSpi},
+    rgbleds::{LedFrame, LedOutput, FRAME_LEN},
+    serial_connection::SharedBoard,
+    transitions::solid,
+    ButtonState, Colour, LED_COUNT,
+};
+
+/// Run `test` on a thread named `main`: on the host, the `ThreadModeRawMutex` of the shared board
+/// can only be used from the main thread, and the tests run on threads of their own
+fn in_thread_mode(test: impl FnOnce() + Send + 'static) {
+    std::thread::Builder::new()
+        .name("main".into())
+        .spawn(test)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+/// Board without gamma correction, so that the leds show the colours as requested
+fn board(buttons: &MockI2c) -> SharedBoard<MockI2c> {
+    let mut board = Board::new(buttons.clone());
+    board.set_calibration(Calibration::raw());
+    Mutex::new(RefCell::new(board))
+}
+
+/// Colour of a led as of the last complete frame
+fn colour(leds: &MockLeds, led_idx: usize) -> (u8, u8, u8) {
+    leds.frame()[led_idx].scaled()
+}
+
+#[test]
+fn leds_start_off() {
+    let spi = MockSpi::new();
+    let leds = spi.leds();
+    block_on(LedOutput::new(spi));
+    assert!((0..LED_COUNT).all(|led_idx| colour(&leds, led_idx) == (0, 0, 0)));
+}
+
+#[test]
+fn frames_are_framed_as_the_leds_expect() {
+    let frame = LedFrame::new();
+    let bytes = frame.as_bytes();
+    assert_eq!(bytes.len(), FRAME_LEN);
+    // Start frame, a word per led and at least 4 bytes of end frame
+    assert_eq!(bytes.len(), 4 + 4 * LED_COUNT + 4);
+    assert_eq!(bytes[..4], [0; 4]);
+    assert!(bytes[4..4 + 4 * LED_COUNT]
+        .chunks(4)
+        .all(|word| word == [0b11100000, 0, 0, 0]));
+    assert_eq!(bytes[4 + 4 * LED_COUNT..], [0; 4]);
+}
+
+#[test]
+fn frames_are_sent_one_refresh_after_rendering() {
+    in_thread_mode(|| {
+        let buttons = MockI2c::new();
+        let spi = MockSpi::new();
+        let leds = spi.leds();
+        let board = board(&buttons);
+        block_on(async {
+            let mut output = LedOutput::new(spi).await;
+            board.lock().await.get_mut().add_led_state(
+                3,
+                0,
+                solid(0x1f, Colour::rgb(0xff, 0, 0), 0, 0),
+                &ButtonState::Idle,
+            );
+
+            // The first refresh sends the frame rendered before the state was added
+            output.refresh(&board).await;
+            assert_eq!(colour(&leds, 3), (0, 0, 0));
+            output.refresh(&board).await;
+            assert_eq!(colour(&leds, 3), (0xff, 0, 0));
+            assert_eq!(colour(&leds, 4), (0, 0, 0));
+        });
+    });
+}
+
+#[test]
+fn pressed_buttons_switch_the_state_queue() {
+    in_thread_mode(|| {
+        let buttons = MockI2c::new();
+        let spi = MockSpi::new();
+        let leds = spi.leds();
+        let board = board(&buttons);
+        block_on(async {
+            let mut output = LedOutput::new(spi).await;
+            {
+                let mut board = board.lock().await;
+                let board = board.get_mut();
+                board.add_led_state(
+                    5,
+                    0,
+                    solid(0x1f, Colour::rgb(0, 0, 0xff), 0, 0),
+                    &ButtonState::Idle,
+                );
+                board.add_led_state(
+                    5,
+                    0,
+                    solid(0x1f, Colour::rgb(0, 0xff, 0), 0, 0),
+                    &ButtonState::Pressed,
+                );
+            }
+
+            buttons.press(5);
+            let event = update(&board).await.unwrap();
+            assert_eq!((event.led_idx, event.state), (5, ButtonState::Pressed));
+            output.refresh(&board).await;
+            output.refresh(&board).await;
+            assert_eq!(colour(&leds, 5), (0, 0xff, 0));
+
+            buttons.release(5);
+            let event = update(&board).await.unwrap();
+            assert_eq!((event.led_idx, event.state), (5, ButtonState::Idle));
+            output.refresh(&board).await;
+            output.refresh(&board).await;
+            assert_eq!(colour(&leds, 5), (0, 0, 0xff));
+            assert!(update(&board).await.is_none());
+        });
+    });
+}
+
+/// Read the buttons, returns the first new event
+async fn update(board: &SharedBoard<MockI2c>) -> Option<ButtonEvent> {
+    let mut board = board.lock().await;
+    board.get_mut().update_status().await.unwrap();
+    board.get_mut().pop_event()
+}
@@ -0,0 +1,82 @@
+use pico_soundboard::{
+    flow::{Branch, Condition, Flag, Flow, FlowInputs, FLAG_COUNT},
+    serial_protocol::ParseError,
+    ButtonState,
+};
+
+fn flag_branch(flag: Flag) -> Flow {
+    Flow {
+        repeat_count: 2,
+        branch: Some(Branch {
+            condition: Condition::FlagSet(flag),
+            target: 7,
+        }),
+    }
+}
+
+#[test]
+fn flags_are_the_bits_of_a_byte() {
+    assert!((0..FLAG_COUNT).all(|index| Flag::new(index).is_some()));
+    assert!((FLAG_COUNT..=u8::MAX).all(|index| Flag::new(index).is_none()));
+
+    let last = Flag::new(FLAG_COUNT - 1).unwrap();
+    let inputs = FlowInputs {
+        flags: 0b10000000,
+        ..Default::default()
+    };
+    assert!(Condition::FlagSet(last).holds(&inputs));
+    assert!(!Condition::FlagClear(last).holds(&inputs));
+    assert!(!Condition::FlagSet(Flag::new(0).unwrap()).holds(&inputs));
+}
+
+#[test]
+fn flows_round_trip() {
+    let flow = flag_branch(Flag::new(5).unwrap());
+    let data = flow.to_data(3, &ButtonState::Pressed, 2);
+    assert_eq!(data[..2], [0x83, 0x27]);
+    assert_eq!(Flow::from_data(&data).unwrap(), flow);
+}
+
+#[test]
+fn flags_out_of_range_are_rejected() {
+    let mut data = flag_branch(Flag::new(0).unwrap()).to_data(0, &ButtonState::Idle, 0);
+    data[5] = FLAG_COUNT;
+    assert!(matches!(
+        Flow::from_data(&data),
+        Err(ParseError::InvalidData)
+    ));
+}
+
+#[test]
+fn a_target_without_a_branch_is_rejected() {
+    let flow = Flow {
+        repeat_count: 3,
+        branch: None,
+    };
+    let mut data = flow.to_data(0, &ButtonState::Idle, 4);
+    assert_eq!(Flow::from_data(&data).unwrap(), flow);
+    data[1] |= 5;
+    assert!(matches!(
+        Flow::from_data(&data),
+        Err(ParseError::InvalidData)
+    ));
+
+    let mut data = flow.to_data(0, &ButtonState::Idle, 4);
+    data[5] = 1;
+    assert!(matches!(
+        Flow::from_data(&data),
+        Err(ParseError::InvalidData)
+    ));
+}
+
+#[test]
+fn reserved_bytes_are_rejected() {
+    for byte in 6..8 {
+        let mut data = flag_branch(Flag::new(1).unwrap()).to_data(0, &ButtonState::Idle, 0);
+        data[byte] = 0x01;
+        assert!(matches!(
+            Flow::from_data(&data),
+            Err(ParseError::InvalidData)
+        ));
+    }
+}
@@ -0,0 +1,250 @@
+use pico_soundboard::{
+    calibration::MAX_GAMMA,
+    flow::{Branch, Condition, Flag, Flow, FLAG_COUNT},
+    request::{Request, RequestError, ALL_LEDS},
+    serial_protocol::{AssembledMessage, DeviceError, ParseError, SerialCommand, SerialMessage},
+    transitions::{blink, solid},
+    ButtonState, Colour, LED_COUNT,
+};
+
+/// Decode a request sent in a single frame, the data bytes not given are `0`
+fn decode(command: SerialCommand, bytes: &[u8]) -> Result<Request, RequestError> {
+    let mut data = [0; 8];
+    data[..bytes.len()].copy_from_slice(bytes);
+    let message = SerialMessage::new(command, data, SerialCommand::EndOfStream);
+    Request::try_from(&AssembledMessage::from(message))
+}
+
+fn is_invalid_data(result: Result<Request, RequestError>) -> bool {
+    matches!(result, Err(RequestError::Parse(ParseError::InvalidData)))
+}
+
+fn is_invalid_led(result: Result<Request, RequestError>) -> bool {
+    matches!(
+        result,
+        Err(RequestError::Device(DeviceError::InvalidLedIndex))
+    )
+}
+
+#[test]
+fn commands_sent_by_the_device_are_rejected() {
+    for command in [
+        SerialCommand::EndOfStream,
+        SerialCommand::ToBeContinued,
+        SerialCommand::ButtonEvent,
+        SerialCommand::Reserved,
+    ] {
+        assert!(matches!(
+            decode(command, &[]),
+            Err(RequestError::InvalidCommand)
+        ));
+    }
+}
+
+#[test]
+fn data_bytes_of_commands_without_fields_are_ignored() {
+    assert!(matches!(
+        decode(SerialCommand::Ping, &[0xff; 8]),
+        Ok(Request::Ping)
+    ));
+    assert!(matches!(
+        decode(SerialCommand::UnlockAllButtonStates, &[0xff; 8]),
+        Ok(Request::UnlockAllButtonStates)
+    ));
+}
+
+#[test]
+fn lock_button_state() {
+    assert!(matches!(
+        decode(SerialCommand::LockButtonState, &[0x8f]),
+        Ok(Request::LockButtonState {
+            led_idx: 15,
+            state: ButtonState::Pressed
+        })
+    ));
+    // Bits 6-4 are reserved, every value of the led nibble is a led
+    assert!(is_invalid_data(decode(
+        SerialCommand::LockButtonState,
+        &[0x13]
+    )));
+}
+
+#[test]
+fn lock_all_button_states() {
+    assert!(matches!(
+        decode(SerialCommand::LockAllButtonStates, &[0x80]),
+        Ok(Request::LockAllButtonStates {
+            state: ButtonState::Pressed
+        })
+    ));
+    assert!(is_invalid_data(decode(
+        SerialCommand::LockAllButtonStates,
+        &[0x81]
+    )));
+}
+
+#[test]
+fn unlock_button_state() {
+    assert!(matches!(
+        decode(SerialCommand::UnlockButtonState, &[LED_COUNT as u8 - 1]),
+        Ok(Request::UnlockButtonState { led_idx }) if led_idx == LED_COUNT - 1
+    ));
+    assert!(is_invalid_led(decode(
+        SerialCommand::UnlockButtonState,
+        &[LED_COUNT as u8]
+    )));
+}
+
+#[test]
+fn set_gamma() {
+    assert!(matches!(
+        decode(SerialCommand::SetGamma, &[1, 22, MAX_GAMMA]),
+        Ok(Request::SetGamma { gamma }) if gamma == [1, 22, MAX_GAMMA]
+    ));
+    assert!(is_invalid_data(decode(
+        SerialCommand::SetGamma,
+        &[22, 0, 22]
+    )));
+    assert!(is_invalid_data(decode(
+        SerialCommand::SetGamma,
+        &[22, 22, MAX_GAMMA + 1]
+    )));
+}
+
+#[test]
+fn set_colour_correction() {
+    assert!(matches!(
+        decode(SerialCommand::SetColourCorrection, &[2, 0xff, 0x80, 0x40]),
+        Ok(Request::SetColourCorrection {
+            led_idx: Some(2),
+            correction
+        }) if correction == Colour::rgb(0xff, 0x80, 0x40)
+    ));
+    assert!(matches!(
+        decode(SerialCommand::SetColourCorrection, &[ALL_LEDS]),
+        Ok(Request::SetColourCorrection { led_idx: None, .. })
+    ));
+    assert!(is_invalid_led(decode(
+        SerialCommand::SetColourCorrection,
+        &[LED_COUNT as u8]
+    )));
+}
+
+#[test]
+fn set_flag() {
+    assert!(matches!(
+        decode(SerialCommand::SetFlag, &[FLAG_COUNT - 1, 1]),
+        Ok(Request::SetFlag { flag, value: true }) if flag == Flag::new(FLAG_COUNT - 1).unwrap()
+    ));
+    assert!(is_invalid_data(decode(
+        SerialCommand::SetFlag,
+        &[FLAG_COUNT, 1]
+    )));
+    assert!(is_invalid_data(decode(SerialCommand::SetFlag, &[0, 2])));
+}
+
+#[test]
+fn set_dithering() {
+    assert!(matches!(
+        decode(SerialCommand::SetDithering, &[1]),
+        Ok(Request::SetDithering { enabled: true })
+    ));
+    assert!(is_invalid_data(decode(SerialCommand::SetDithering, &[2])));
+}
+
+#[test]
+fn add_state() {
+    let transition = solid(0x1f, Colour::white(), 100, 2);
+    let payload = transition.to_payload(15, &ButtonState::Pressed, 15);
+    assert!(matches!(
+        Request::try_from(&AssembledMessage::from_payload(SerialCommand::AddState, &payload)),
+        Ok(Request::AddState {
+            led_idx: 15,
+            state_idx: 15,
+            for_state: ButtonState::Pressed,
+            transition: decoded,
+        }) if decoded == transition
+    ));
+
+    // Out of range parameters of a transition are reported apart from the other fields
+    let mut payload = blink(0x1f, Colour::white(), Colour::rgb(0, 0, 0), 50, 50, 0, 0).to_payload(
+        0,
+        &ButtonState::Idle,
+        0,
+    );
+    payload[8] = 101;
+    assert!(matches!(
+        Request::try_from(&AssembledMessage::from_payload(
+            SerialCommand::AddState,
+            &payload
+        )),
+        Err(RequestError::Parse(ParseError::InvalidTransition))
+    ));
+}
+
+#[test]
+fn remove_state() {
+    assert!(matches!(
+        decode(SerialCommand::RemoveState, &[0x83, 0xf0]),
+        Ok(Request::RemoveState {
+            led_idx: 3,
+            state_idx: 15,
+            for_state: ButtonState::Pressed
+        })
+    ));
+    assert!(is_invalid_data(decode(
+        SerialCommand::RemoveState,
+        &[0x43, 0x10]
+    )));
+    // The low nibble of the slot byte is reserved
+    assert!(is_invalid_data(decode(
+        SerialCommand::RemoveState,
+        &[0x03, 0x11]
+    )));
+}
+
+#[test]
+fn clear_states() {
+    assert!(matches!(
+        decode(SerialCommand::ClearStates, &[0x05]),
+        Ok(Request::ClearStates {
+            led_idx: 5,
+            for_state: ButtonState::Idle
+        })
+    ));
+    assert!(is_invalid_data(decode(SerialCommand::ClearStates, &[0x25])));
+}
+
+#[test]
+fn set_state_flow() {
+    let flow = Flow {
+        repeat_count: 1,
+        branch: Some(Branch {
+            condition: Condition::FlagSet(Flag::new(2).unwrap()),
+            target: 4,
+        }),
+    };
+    let data = flow.to_data(6, &ButtonState::Idle, 9);
+    assert!(matches!(
+        decode(SerialCommand::SetStateFlow, &data),
+        Ok(Request::SetStateFlow {
+            led_idx: 6,
+            state_idx: 9,
+            for_state: ButtonState::Idle,
+            flow: decoded,
+        }) if decoded == flow
+    ));
+
+    let mut reserved = data;
+    reserved[0] |= 0x70;
+    assert!(is_invalid_data(decode(
+        SerialCommand::SetStateFlow,
+        &reserved
+    )));
+    let mut reserved = data;
+    reserved[7] = 1;
+    assert!(is_invalid_data(decode(
+        SerialCommand::SetStateFlow,
+        &reserved
+    )));
+}
@@ -0,0 +1,155 @@
+use core::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+
+use embassy_sync::mutex::Mutex;
+use embassy_time::Timer;
+use pico_soundboard::{
+    board::Board,
+    host::Client,
+    mock::{block_on, MockI2c},
+    serial_connection::{apply_connection_defaults, serial_loop, SerialTransport, SharedBoard},
+    serial_protocol::ProtocolVersion,
+    transitions::{fade_in, solid, Easing},
+    ButtonState, Colour, LED_COUNT,
+};
+
+/// Device end of an in-memory link, polled so that the timers of the serial loop keep running
+struct DeviceEnd {
+    received: Receiver<Vec<u8>>,
+    sent: Sender<Vec<u8>>,
+}
+
+/// The host closed its end of the link
+#[derive(Debug)]
+struct Disconnected;
+
+impl SerialTransport for DeviceEnd {
+    type Error = Disconnected;
+
+    async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, Self::Error> {
+        loop {
+            match self.received.try_recv() {
+                Ok(packet) => {
+                    data[..packet.len()].copy_from_slice(&packet);
+                    return Ok(packet.len());
+                }
+                Err(TryRecvError::Empty) => Timer::after_millis(1).await,
+                Err(TryRecvError::Disconnected) => return Err(Disconnected),
+            }
+        }
+    }
+
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.sent.send(data.to_vec()).map_err(|_| Disconnected)
+    }
+}
+
+/// Host end of an in-memory link, a read returns `WouldBlock` when nothing was sent
+struct HostEnd {
+    received: Receiver<Vec<u8>>,
+    sent: Sender<Vec<u8>>,
+    pending: VecDeque<u8>,
+}
+
+impl Read for HostEnd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.received.try_recv() {
+                Ok(packet) => self.pending.extend(packet),
+                Err(TryRecvError::Empty) => return Err(ErrorKind::WouldBlock.into()),
+                Err(TryRecvError::Disconnected) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf.iter_mut()
+            .zip(self.pending.drain(..n))
+            .for_each(|(byte, pending)| *byte = pending);
+        Ok(n)
+    }
+}
+
+impl Write for HostEnd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Packets of at most 64 bytes, as on USB
+        for packet in buf.chunks(64) {
+            self.sent
+                .send(packet.to_vec())
+                .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn link() -> (HostEnd, DeviceEnd) {
+    let (to_device, from_host) = channel();
+    let (to_host, from_device) = channel();
+    (
+        HostEnd {
+            received: from_device,
+            sent: to_device,
+            pending: VecDeque::new(),
+        },
+        DeviceEnd {
+            received: from_host,
+            sent: to_host,
+        },
+    )
+}
+
+/// Serve the device end until the host disconnects, on a thread named `main`: on the host, the
+/// `ThreadModeRawMutex` of the shared board can only be used from the main thread
+fn spawn_device(mut device: DeviceEnd) -> thread::JoinHandle<bool> {
+    thread::Builder::new()
+        .name("main".into())
+        .spawn(move || {
+            let board: SharedBoard<MockI2c> = Mutex::new(RefCell::new(Board::new(MockI2c::new())));
+            block_on(async {
+                apply_connection_defaults(board.lock().await.get_mut());
+                serial_loop(&mut device, &board).await.is_err()
+            })
+        })
+        .unwrap()
+}
+
+#[test]
+fn requests_round_trip_through_the_serial_loop() {
+    let (host, device) = link();
+    let device = spawn_device(device);
+    let mut client = Client::new(host);
+
+    assert_eq!(
+        client.handshake(ProtocolVersion::V2).unwrap(),
+        ProtocolVersion::V2
+    );
+    // An eased fade is a chain of two frames
+    let fade = fade_in(0x1f, Colour::rgb(0xff, 0x80, 0), 500, 0).with_easing(Easing::Sine);
+    client.add_state(3, 1, ButtonState::Pressed, fade).unwrap();
+
+    let state = client.sync().unwrap();
+    assert!(state.keyboard_input_enabled);
+    assert_eq!(state.leds.len(), LED_COUNT);
+    let off = solid(0x00, Colour::white(), 0, 0);
+    for led in &state.leds {
+        let mut expected = vec![(ButtonState::Idle, 0, off)];
+        if led.led_idx == 3 {
+            expected.push((ButtonState::Pressed, 1, fade));
+        }
+        let states: Vec<_> = led
+            .states
+            .iter()
+            .map(|state| (state.for_state, state.state_idx, state.transition))
+            .collect();
+        assert_eq!(states, expected);
+    }
+
+    // The serial loop ends once the host closes the link
+    drop(client);
+    assert!(device.join().unwrap());
+}
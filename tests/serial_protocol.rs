@@ -0,0 +1,297 @@
+use pico_soundboard::{
+    request::Request,
+    serial_protocol::{
+        crc8, split_payload, Framer, Framing, MessageAssembler, ParseError, ProtocolVersion,
+        SerialCommand, SerialMessage, V1_FRAME_SIZE, V2_FRAME_SIZE,
+    },
+    transitions::{fade_in, fade_out, Easing, Transition},
+    ButtonState, Colour,
+};
+
+fn message(command: SerialCommand, data: [u8; 8], end_byte: SerialCommand) -> SerialMessage {
+    SerialMessage::new(command, data, end_byte)
+}
+
+fn ping() -> SerialMessage {
+    message(SerialCommand::Ping, [0; 8], SerialCommand::EndOfStream)
+}
+
+#[test]
+fn crc8_is_smbus() {
+    assert_eq!(crc8(b"123456789"), 0xf4);
+    assert_eq!(crc8(&[]), 0x00);
+}
+
+#[test]
+fn v1_frames_are_the_message() {
+    let framing = Framing::new();
+    let frame = framing.encode(&ping());
+    assert_eq!(frame.len(), V1_FRAME_SIZE);
+    assert_eq!(&frame[..], &ping().to_bytes());
+}
+
+#[test]
+fn v2_frames_round_trip() {
+    let mut sender = Framing::new();
+    sender.set_version(ProtocolVersion::V2);
+    sender.set_sequence(0x2a);
+    let sent = message(
+        SerialCommand::AddState,
+        [1, 2, 3, 4, 5, 6, 7, 8],
+        SerialCommand::ToBeContinued,
+    );
+    let frame = sender.encode(&sent);
+    assert_eq!(frame.len(), V2_FRAME_SIZE);
+    assert_eq!(frame[1], 0x2a);
+    assert_eq!(frame[V2_FRAME_SIZE - 1], crc8(&frame[..V2_FRAME_SIZE - 1]));
+
+    let mut receiver = Framing::new();
+    receiver.set_version(ProtocolVersion::V2);
+    let received = receiver.decode(&frame).unwrap();
+    assert_eq!(received.to_bytes(), sent.to_bytes());
+    assert_eq!(receiver.sequence(), 0x2a);
+}
+
+#[test]
+fn v2_frames_with_a_bad_checksum_keep_their_sequence() {
+    let mut sender = Framing::new();
+    sender.set_version(ProtocolVersion::V2);
+    sender.set_sequence(7);
+    let mut frame = sender.encode(&ping());
+    frame[3] ^= 0x01;
+
+    let mut receiver = Framing::new();
+    receiver.set_version(ProtocolVersion::V2);
+    assert!(matches!(
+        receiver.decode(&frame),
+        Err(ParseError::InvalidChecksum)
+    ));
+    assert_eq!(receiver.sequence(), 7);
+}
+
+#[test]
+fn framer_joins_frames_split_across_packets() {
+    let bytes = ping().to_bytes();
+    let mut framer = Framer::new();
+    framer.push(&bytes[..4]).unwrap();
+    assert!(framer.next_frame(ProtocolVersion::V1).is_none());
+    framer.push(&bytes[4..]).unwrap();
+    let frame = framer.next_frame(ProtocolVersion::V1).unwrap().unwrap();
+    assert_eq!(&frame[..], &bytes);
+    assert!(framer.is_empty());
+}
+
+#[test]
+fn framer_skips_garbage_once() {
+    let mut framer = Framer::new();
+    framer.push(&[0x00, 0x01, 0x02]).unwrap();
+    framer.push(&ping().to_bytes()).unwrap();
+    assert!(matches!(
+        framer.next_frame(ProtocolVersion::V1),
+        Some(Err(ParseError::InvalidCommand))
+    ));
+    let frame = framer.next_frame(ProtocolVersion::V1).unwrap().unwrap();
+    assert_eq!(&frame[..], &ping().to_bytes());
+    assert!(framer.next_frame(ProtocolVersion::V1).is_none());
+}
+
+#[test]
+fn framer_resynchronises_on_a_missing_end_byte() {
+    let mut bytes = ping().to_bytes();
+    bytes[V1_FRAME_SIZE - 1] = 0x00;
+    let mut framer = Framer::new();
+    framer.push(&bytes).unwrap();
+    framer.push(&ping().to_bytes()).unwrap();
+    assert!(matches!(
+        framer.next_frame(ProtocolVersion::V1),
+        Some(Err(ParseError::InvalidEndByte))
+    ));
+    let frame = framer.next_frame(ProtocolVersion::V1).unwrap().unwrap();
+    assert_eq!(&frame[..], &ping().to_bytes());
+}
+
+#[test]
+fn framer_returns_the_partial_frame() {
+    let bytes = ping().to_bytes();
+    let mut framer = Framer::new();
+    framer.push(&bytes[..6]).unwrap();
+    assert!(framer.next_frame(ProtocolVersion::V1).is_none());
+    assert_eq!(&framer.take_partial()[..], &bytes[..6]);
+    assert!(framer.is_empty());
+}
+
+#[test]
+fn assembler_joins_chains() {
+    let payload: Vec<u8> = (0..20).collect();
+    let mut assembler = MessageAssembler::new();
+    let mut frames = split_payload(SerialCommand::AddState, &payload);
+    assert!(assembler
+        .push(frames.next().unwrap(), None)
+        .unwrap()
+        .is_none());
+    assert!(assembler
+        .push(frames.next().unwrap(), None)
+        .unwrap()
+        .is_none());
+    assert!(assembler.in_progress());
+    let assembled = assembler
+        .push(frames.next().unwrap(), None)
+        .unwrap()
+        .unwrap();
+    assert!(frames.next().is_none());
+    assert!(!assembler.in_progress());
+
+    assert!(matches!(assembled.get_command(), SerialCommand::AddState));
+    assert!(assembled.is_multi_frame());
+    // Padded to whole frames
+    assert_eq!(&assembled.get_payload()[..20], &payload[..]);
+    assert_eq!(&assembled.get_payload()[20..], &[0; 4]);
+    assert_eq!(assembled.header().get_data(), &[0, 1, 2, 3, 4, 5, 6, 7]);
+}
+
+#[test]
+fn assembler_passes_single_frames_through() {
+    let mut assembler = MessageAssembler::new();
+    let assembled = assembler.push(ping(), None).unwrap().unwrap();
+    assert!(!assembled.is_multi_frame());
+    assert_eq!(assembled.header().to_bytes(), ping().to_bytes());
+}
+
+#[test]
+fn another_command_interrupts_the_chain() {
+    let first = message(
+        SerialCommand::AddState,
+        [1; 8],
+        SerialCommand::ToBeContinued,
+    );
+    let mut assembler = MessageAssembler::new();
+    assert!(assembler.push(first.clone(), None).unwrap().is_none());
+    assert!(assembler
+        .interrupted_by(
+            &message(SerialCommand::AddState, [2; 8], SerialCommand::EndOfStream),
+            None
+        )
+        .is_none());
+
+    let interrupted = assembler.interrupted_by(&ping(), None).unwrap();
+    assert_eq!(interrupted.to_bytes(), first.to_bytes());
+    // The chain is dropped and the new message handled on its own
+    let assembled = assembler.push(ping(), None).unwrap().unwrap();
+    assert_eq!(assembled.get_payload(), &[0; 8]);
+    assert!(!assembler.in_progress());
+}
+
+fn add_state(transition: Transition) -> Request {
+    Request::AddState {
+        led_idx: 3,
+        state_idx: 1,
+        for_state: ButtonState::Idle,
+        transition,
+    }
+}
+
+#[test]
+fn frames_of_a_chain_are_numbered_consecutively() {
+    let eased = fade_out(0x1f, Colour::white(), 500, 0).with_easing(Easing::Sine);
+    let frames = add_state(eased).to_messages();
+    assert_eq!(frames.len(), 2);
+    let mut assembler = MessageAssembler::new();
+    assert!(assembler
+        .push(frames[0].clone(), Some(0xff))
+        .unwrap()
+        .is_none());
+    assert!(assembler.interrupted_by(&frames[1], Some(0x00)).is_none());
+    let assembled = assembler
+        .push(frames[1].clone(), Some(0x00))
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        Request::try_from(&assembled),
+        Ok(Request::AddState { transition, .. }) if transition == eased
+    ));
+}
+
+#[test]
+fn a_retried_request_after_a_lost_frame_interrupts_the_chain() {
+    let eased = add_state(fade_out(0x1f, Colour::white(), 500, 0).with_easing(Easing::Sine));
+    let retried = fade_in(0x10, Colour::rgb(0, 0, 0xff), 100, 2);
+    let frames = eased.to_messages();
+    let retry = add_state(retried).to_messages();
+    assert_eq!(retry.len(), 1);
+
+    // The second frame of the chain (number 6) is lost, the host sends its next request
+    let mut assembler = MessageAssembler::new();
+    assert!(assembler
+        .push(frames[0].clone(), Some(5))
+        .unwrap()
+        .is_none());
+    let interrupted = assembler.interrupted_by(&retry[0], Some(7)).unwrap();
+    assert_eq!(interrupted.to_bytes(), frames[0].to_bytes());
+    // The NACK of the dropped chain is numbered as its last frame
+    assert_eq!(assembler.sequence(), Some(5));
+
+    let assembled = assembler.push(retry[0].clone(), Some(7)).unwrap().unwrap();
+    assert!(!assembled.is_multi_frame());
+    assert!(matches!(
+        Request::try_from(&assembled),
+        Ok(Request::AddState { transition, .. }) if transition == retried
+    ));
+    assert!(!assembler.in_progress());
+}
+
+#[test]
+fn v1_chains_are_only_interrupted_by_another_command() {
+    let eased = add_state(fade_out(0x1f, Colour::white(), 500, 0).with_easing(Easing::Sine));
+    let frames = eased.to_messages();
+    let retry = add_state(fade_in(0x10, Colour::rgb(0, 0, 0xff), 100, 2)).to_messages();
+
+    // Without sequence numbers the retried request continues the chain
+    let mut assembler = MessageAssembler::new();
+    assert!(assembler.push(frames[0].clone(), None).unwrap().is_none());
+    assert!(assembler.interrupted_by(&retry[0], None).is_none());
+    let assembled = assembler.push(retry[0].clone(), None).unwrap().unwrap();
+    assert!(assembled.is_multi_frame());
+    assert_eq!(assembled.header().to_bytes(), frames[0].to_bytes());
+}
+
+#[test]
+fn assembler_drops_the_chain_on_an_invalid_end_byte() {
+    let mut assembler = MessageAssembler::new();
+    assembler
+        .push(
+            message(
+                SerialCommand::AddState,
+                [1; 8],
+                SerialCommand::ToBeContinued,
+            ),
+            None,
+        )
+        .unwrap();
+    assert!(matches!(
+        assembler.push(
+            message(SerialCommand::AddState, [2; 8], SerialCommand::Ack),
+            None
+        ),
+        Err(ParseError::InvalidEndByte)
+    ));
+    assert!(!assembler.in_progress());
+}
+
+#[test]
+fn assembler_rejects_overlong_chains() {
+    let mut assembler = MessageAssembler::new();
+    let result = (0..)
+        .map(|_| {
+            assembler.push(
+                message(
+                    SerialCommand::AddState,
+                    [0; 8],
+                    SerialCommand::ToBeContinued,
+                ),
+                None,
+            )
+        })
+        .find(|result| result.is_err());
+    assert!(matches!(result, Some(Err(ParseError::PayloadTooLong))));
+    assert!(!assembler.in_progress());
+}
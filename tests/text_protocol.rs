@@ -0,0 +1,98 @@
+use pico_soundboard::{
+    request::Request,
+    text_protocol::{parse_line, TextCommand, TextError},
+    transitions::{crossfade, fade_in, Easing},
+    ButtonState, Colour,
+};
+
+fn request(line: &str) -> Request {
+    match parse_line(line) {
+        Ok(TextCommand::Request(request)) => request,
+        Ok(_) => panic!("`{}` is not a request", line),
+        Err(error) => panic!("`{}` is rejected: {:?}", line, error),
+    }
+}
+
+#[test]
+fn states_are_added_as_in_binary_mode() {
+    let fade = fade_in(0x1f, Colour::rgb(0xff, 0x80, 0x00), 500, 1).with_easing(Easing::Sine);
+    assert!(matches!(
+        request("state add 3 pressed fade_in 1f ff8000 500 slot=2 next=1 ease=sine"),
+        Request::AddState {
+            led_idx: 3,
+            state_idx: 2,
+            for_state: ButtonState::Pressed,
+            transition,
+        } if transition == fade
+    ));
+
+    let blend = crossfade(Colour::white(), Colour::rgb(0, 0, 0xff), 0x1f, 0x08, 100, 0);
+    assert!(matches!(
+        request("state add 0 idle crossfade 1f #ffffff 100 to=#0000ff to_brightness=08"),
+        Request::AddState { transition, .. } if transition == blend
+    ));
+}
+
+#[test]
+fn settings_take_human_readable_values() {
+    assert!(matches!(
+        request("gamma 2.2"),
+        Request::SetGamma {
+            gamma: [22, 22, 22]
+        }
+    ));
+    assert!(matches!(
+        request("correction all ff8040"),
+        Request::SetColourCorrection { led_idx: None, correction } if correction == Colour::rgb(0xff, 0x80, 0x40)
+    ));
+    assert!(matches!(
+        request("lock 15 pressed"),
+        Request::LockButtonState {
+            led_idx: 15,
+            state: ButtonState::Pressed
+        }
+    ));
+    assert!(matches!(parse_line("binary"), Ok(TextCommand::Binary)));
+}
+
+#[test]
+fn invalid_lines_are_rejected() {
+    assert!(matches!(
+        parse_line("blink"),
+        Err(TextError::UnknownCommand)
+    ));
+    assert!(matches!(parse_line(""), Err(TextError::MissingArgument(_))));
+    assert!(matches!(
+        parse_line("state add 3 idle"),
+        Err(TextError::MissingArgument("transition"))
+    ));
+    assert!(matches!(
+        parse_line("dithering maybe"),
+        Err(TextError::InvalidArgument("on|off"))
+    ));
+    // Only fades, crossfades and keyframes have a curve
+    assert!(matches!(
+        parse_line("state add 3 idle solid 1f ffffff 0 ease=sine"),
+        Err(TextError::InvalidArgument("ease"))
+    ));
+}
+
+#[test]
+fn out_of_range_values_are_rejected() {
+    assert!(matches!(
+        parse_line("flag 8 on"),
+        Err(TextError::InvalidArgument("flag"))
+    ));
+    assert!(matches!(
+        parse_line("unlock 16"),
+        Err(TextError::InvalidArgument("led"))
+    ));
+    assert!(matches!(
+        parse_line("gamma 0"),
+        Err(TextError::InvalidArgument("gamma"))
+    ));
+    assert!(matches!(
+        parse_line("gamma 2.25"),
+        Err(TextError::InvalidArgument("gamma"))
+    ));
+}
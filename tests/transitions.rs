@@ -0,0 +1,145 @@
+use pico_soundboard::{
+    serial_protocol::ParseError,
+    transitions::{
+        blink, crossfade, fade_in, fade_out, flicker, hue_cycle, keyframes, solid, ColourSpace,
+        Easing, Keyframe, Keyframes, Transition, TransitionResult,
+    },
+    ButtonState, Colour,
+};
+
+fn round_trip(transition: Transition) -> Transition {
+    let payload = transition.to_payload(9, &ButtonState::Pressed, 4);
+    assert_eq!(payload[0], 1 << 7 | (transition.kind() as u8) << 4 | 9);
+    assert_eq!(payload[1] >> 4, 4);
+    Transition::try_from(&payload[..]).unwrap()
+}
+
+fn assert_round_trip(transition: Transition) {
+    assert_eq!(round_trip(transition), transition);
+}
+
+fn heartbeat() -> Keyframes {
+    Keyframes::from_slice(&[
+        Keyframe::new(0, 0, Colour::rgb(0x20, 0, 0)),
+        Keyframe::new(100, 0x1f, Colour::rgb(0xff, 0, 0)),
+        Keyframe::new(100, 0x10, Colour::rgb(0xff, 0x40, 0)),
+        Keyframe::new(900, 0, Colour::rgb(0x20, 0, 0)),
+    ])
+    .unwrap()
+}
+
+#[test]
+fn transitions_round_trip() {
+    let colour = Colour::rgb(0x12, 0x34, 0x56);
+    assert_round_trip(solid(0x1f, colour, 0, 3));
+    assert_round_trip(fade_out(0x1f, colour, 500, 1));
+    assert_round_trip(fade_in(0x10, colour, 65535, 15));
+    assert_round_trip(crossfade(
+        colour,
+        Colour::rgb(0xff, 0, 0),
+        0x1f,
+        0x04,
+        300,
+        2,
+    ));
+    assert_round_trip(blink(0x1f, colour, Colour::rgb(1, 2, 3), 250, 30, 12, 0));
+    assert_round_trip(hue_cycle(0x4ff, -256, 0xc0, 0x1f, 0, 0));
+    assert_round_trip(flicker(colour, 0x80, 12, 0xdeadbeef, 1000, 1));
+    assert_round_trip(keyframes(heartbeat(), 3, 5));
+}
+
+#[test]
+fn curves_and_colour_spaces_round_trip() {
+    let colour = Colour::rgb(0x12, 0x34, 0x56);
+    assert_round_trip(fade_out(0xff, colour, 500, 1).with_easing(Easing::Quadratic));
+    assert_round_trip(fade_in(0x1f, colour, 500, 1).with_easing(Easing::EaseInOut));
+    assert_round_trip(
+        crossfade(colour, Colour::rgb(0xff, 0, 0), 0x1f, 0x1f, 300, 2)
+            .with_easing(Easing::Sine)
+            .with_colour_space(ColourSpace::Hsv),
+    );
+    assert_round_trip(
+        keyframes(heartbeat(), 0, 0)
+            .with_easing(Easing::Exponential)
+            .with_colour_space(ColourSpace::Hsv),
+    );
+}
+
+#[test]
+fn payloads_are_whole_frames() {
+    let colour = Colour::rgb(0x12, 0x34, 0x56);
+    let len = |transition: Transition| transition.to_payload(0, &ButtonState::Idle, 0).len();
+    assert_eq!(len(solid(0x1f, colour, 0, 0)), 8);
+    // Linear fades are sent as before the curves were added
+    assert_eq!(len(fade_in(0x1f, colour, 500, 0)), 8);
+    assert_eq!(
+        len(fade_in(0x1f, colour, 500, 0).with_easing(Easing::Cubic)),
+        16
+    );
+    assert_eq!(len(flicker(colour, 0x80, 12, 1, 0, 0)), 16);
+    // 4 keyframes of 6 bytes after the first frame
+    assert_eq!(len(keyframes(heartbeat(), 0, 0)), 8 + 24);
+}
+
+#[test]
+fn upper_brightness_bits_do_not_select_a_curve() {
+    // `fade_out` of led 2 with a brightness of `0x4f`, as sent by older hosts
+    let payload = [0x12, 0x00, 0x4f, 0x10, 0x20, 0x30, 0x01, 0xf4];
+    assert_eq!(
+        Transition::try_from(&payload[..]).unwrap(),
+        fade_out(0x4f, Colour::rgb(0x10, 0x20, 0x30), 500, 0)
+    );
+}
+
+#[test]
+fn invalid_payloads_are_rejected() {
+    let colour = Colour::rgb(0x12, 0x34, 0x56);
+    let mut eased = fade_in(0x1f, colour, 500, 0)
+        .with_easing(Easing::Sine)
+        .to_payload(0, &ButtonState::Idle, 0);
+    eased[8] = 6;
+    assert!(matches!(
+        Transition::try_from(&eased[..]),
+        Err(ParseError::InvalidTransition)
+    ));
+
+    let crossfade =
+        crossfade(colour, colour, 0x1f, 0x1f, 300, 0).to_payload(0, &ButtonState::Idle, 0);
+    assert!(matches!(
+        Transition::try_from(&crossfade[..8]),
+        Err(ParseError::InvalidMessageLength)
+    ));
+
+    let mut blink = blink(0x1f, colour, colour, 250, 50, 0, 0).to_payload(0, &ButtonState::Idle, 0);
+    blink[8] = 101;
+    assert!(matches!(
+        Transition::try_from(&blink[..]),
+        Err(ParseError::InvalidTransition)
+    ));
+}
+
+/// Levels at the start of the steps of a flicker
+fn flicker_levels(seed: u32, intensity: u8) -> Vec<u16> {
+    // 10 steps per 1000 ticks
+    let flicker = flicker(Colour::rgb(0xff, 0x80, 0), intensity, 10, seed, 0, 0);
+    (0..8)
+        .map(|step| match flicker.render(step * 100) {
+            TransitionResult::InProgress(state) => state.level,
+            TransitionResult::Finished(_) => panic!("a flicker without a duration never ends"),
+        })
+        .collect()
+}
+
+/// The levels are documented for hosts previewing a flicker, so they must not change
+#[test]
+fn flicker_levels_are_pinned() {
+    assert_eq!(
+        flicker_levels(0x1234_5678, 0xff),
+        [59317, 61101, 55673, 18652, 448, 34176, 57713, 15771]
+    );
+    assert_eq!(
+        flicker_levels(0, 0x80),
+        [39869, 54976, 54038, 65112, 59412, 60615, 47531, 60537]
+    );
+    assert_eq!(flicker_levels(0, 0), [u16::MAX; 8]);
+}